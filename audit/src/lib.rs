@@ -0,0 +1,262 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! An [rtld-audit](https://man7.org/linux/man-pages/man7/rtld-audit.7.html) library which primes
+//! every object as the dynamic loader maps it, including those `dlopen`ed later by any thread.
+//!
+//! Use it via `LD_AUDIT=/path/to/libpage_primer_audit.so`. It's configured by the `PAGE_PRIMER`
+//! environment variable as described in [`page_primer::Spec::parse`], e.g.
+//! `PAGE_PRIMER=mlock,remap=auto`. Set `PAGE_PRIMER_AUDIT_LOG=1` to print the results to stderr.
+//!
+//! If `PAGE_PRIMER` is unset or empty, nothing happens, as with `page-primer-preload`.
+//!
+//! Objects are primed from `la_activity` once the loader reports `LA_ACT_CONSISTENT`. At that
+//! point, the new objects are mapped but not yet relocated or returned from `dlopen`, and the
+//! loader lock is held, so no other thread can be using them. Thus remapping is safe even in a
+//! multithreaded program, unlike with [`page_primer::Options::run`].
+
+use page_primer::Spec;
+use std::ffi::CStr;
+use std::sync::{Mutex, Once, PoisonError};
+
+const LAV_CURRENT: libc::c_uint = 1;
+const LA_ACT_CONSISTENT: libc::c_uint = 0;
+
+#[cfg(target_pointer_width = "64")]
+type Ehdr = libc::Elf64_Ehdr;
+#[cfg(target_pointer_width = "32")]
+type Ehdr = libc::Elf32_Ehdr;
+#[cfg(target_pointer_width = "64")]
+type Phdr = libc::Elf64_Phdr;
+#[cfg(target_pointer_width = "32")]
+type Phdr = libc::Elf32_Phdr;
+
+/// The public prefix of glibc's `struct link_map`, from `<link.h>`.
+#[repr(C)]
+pub struct LinkMap {
+    l_addr: usize,
+    l_name: *const libc::c_char,
+    l_ld: *const libc::c_void,
+    l_next: *const LinkMap,
+    l_prev: *const LinkMap,
+}
+
+/// Addresses of the `LinkMap`s opened since the link map was last consistent.
+static PENDING: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// Ensures warnings about `PAGE_PRIMER` are printed only once.
+static WARNED: Once = Once::new();
+
+/// Returns the configuration from `PAGE_PRIMER`, printing any warnings the first time, or
+/// `None` if it's unset or empty.
+fn spec() -> Option<Spec> {
+    let spec = match std::env::var("PAGE_PRIMER") {
+        Ok(s) if !s.is_empty() => Spec::parse(&s),
+        _ => return None,
+    };
+    if !spec.quiet {
+        WARNED.call_once(|| {
+            for w in &spec.warnings {
+                eprintln!("page-primer-audit: ignoring PAGE_PRIMER {w}");
+            }
+        });
+    }
+    Some(spec)
+}
+
+/// Runs `f`, returning `fallback` rather than unwinding into the dynamic loader if it panics.
+fn catch<T>(fallback: T, f: impl FnOnce() -> T) -> T {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(fallback)
+}
+
+/// Negotiates the audit interface version with the dynamic loader.
+#[no_mangle]
+pub extern "C" fn la_version(version: libc::c_uint) -> libc::c_uint {
+    match version {
+        0 => 0,
+        _ => LAV_CURRENT,
+    }
+}
+
+/// Notes a newly loaded object, to be primed once the link map is consistent.
+///
+/// # Safety
+///
+/// Must only be called by the dynamic loader.
+#[no_mangle]
+pub unsafe extern "C" fn la_objopen(
+    map: *mut LinkMap,
+    _lmid: libc::c_long,
+    cookie: *mut libc::uintptr_t,
+) -> libc::c_uint {
+    catch(0, || {
+        unsafe { *cookie = map as libc::uintptr_t };
+        PENDING
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(map as usize);
+        0
+    })
+}
+
+/// Forgets an object unloaded before it was primed.
+///
+/// # Safety
+///
+/// Must only be called by the dynamic loader.
+#[no_mangle]
+pub unsafe extern "C" fn la_objclose(cookie: *mut libc::uintptr_t) -> libc::c_uint {
+    catch(0, || {
+        let map = unsafe { *cookie };
+        PENDING
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|&m| m != map);
+        0
+    })
+}
+
+/// Primes the objects loaded since the link map was last consistent.
+///
+/// # Safety
+///
+/// Must only be called by the dynamic loader.
+#[no_mangle]
+pub unsafe extern "C" fn la_activity(_cookie: *mut libc::uintptr_t, flag: libc::c_uint) {
+    if flag != LA_ACT_CONSISTENT {
+        return;
+    }
+    catch((), || unsafe { prime_pending() })
+}
+
+/// Primes and forgets the objects in [`PENDING`].
+unsafe fn prime_pending() {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap_or_else(PoisonError::into_inner));
+    let Some(spec) = spec() else {
+        return;
+    };
+    let log =
+        !spec.quiet && std::env::var_os("PAGE_PRIMER_AUDIT_LOG").is_some_and(|v| !v.is_empty());
+    let mut infos = Vec::with_capacity(pending.len());
+    for map in pending {
+        let map = unsafe { &*(map as *const LinkMap) };
+        let name = unsafe { CStr::from_ptr(map.l_name) };
+        let Some((phdr, phnum)) = (unsafe { program_headers(map.l_addr, name) }) else {
+            if log {
+                eprintln!("page-primer-audit: can't find program headers of {name:?}");
+            }
+            continue;
+        };
+        let mut info: libc::dl_phdr_info = unsafe { std::mem::zeroed() };
+        info.dlpi_addr = map.l_addr as _;
+        info.dlpi_name = map.l_name;
+        info.dlpi_phdr = phdr;
+        info.dlpi_phnum = phnum;
+        infos.push(info);
+    }
+
+    if infos.is_empty() {
+        return;
+    }
+
+    // Prime them together, so the `RLIMIT_MEMLOCK` preflight covers them all at once.
+    let out = unsafe { spec.options.run_objects(&infos) };
+    if log {
+        out.eprint();
+    }
+}
+
+/// Returns the load bias of the main executable, computed from the auxiliary vector as the
+/// dynamic loader does.
+fn main_bias() -> usize {
+    let phdr = unsafe { libc::getauxval(libc::AT_PHDR) } as usize;
+    let phnum = unsafe { libc::getauxval(libc::AT_PHNUM) } as usize;
+    if phdr == 0 {
+        return 0;
+    }
+    let phdrs = unsafe { std::slice::from_raw_parts(phdr as *const Phdr, phnum) };
+    phdrs
+        .iter()
+        .find(|p| p.p_type == libc::PT_PHDR)
+        .map_or(0, |p| phdr.wrapping_sub(p.p_vaddr as usize))
+}
+
+/// Returns the program headers of the object named `name` loaded with bias `l_addr`.
+///
+/// The main executable's come from the auxiliary vector. It's identified by its bias, as an empty
+/// name is shared by others, such as the vDSO. Other objects aren't visible to `dl_iterate_phdr`
+/// from this library's link-map namespace, so their ELF header is read from the start of their
+/// lowest segment, which is at virtual address 0 for shared objects.
+unsafe fn program_headers(l_addr: usize, name: &CStr) -> Option<(*const Phdr, u16)> {
+    if name.to_bytes().is_empty() && l_addr == main_bias() {
+        let phdr = unsafe { libc::getauxval(libc::AT_PHDR) } as usize;
+        let phnum = unsafe { libc::getauxval(libc::AT_PHNUM) } as u16;
+        return match phdr {
+            0 => None,
+            _ => Some((phdr as *const Phdr, phnum)),
+        };
+    }
+
+    // Check the header is mapped before reading it; `mincore` fails with `ENOMEM` otherwise.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let mut vec = 0u8;
+    if l_addr == 0
+        || l_addr % page_size != 0
+        || unsafe { libc::mincore(l_addr as *mut libc::c_void, page_size, &mut vec) } != 0
+    {
+        return None;
+    }
+    let ehdr = unsafe { &*(l_addr as *const Ehdr) };
+    if ehdr.e_ident[..4] != *b"\x7fELF"
+        || usize::from(ehdr.e_phentsize) != std::mem::size_of::<Phdr>()
+    {
+        return None;
+    }
+    Some((
+        (l_addr + ehdr.e_phoff as usize) as *const Phdr,
+        ehdr.e_phnum,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn cb(
+        info: *mut libc::dl_phdr_info,
+        _size: libc::size_t,
+        data: *mut libc::c_void,
+    ) -> libc::c_int {
+        let found = unsafe { &mut *(data as *mut Vec<(String, usize, usize, usize)>) };
+        let info = unsafe { &*info };
+        let name = unsafe { CStr::from_ptr(info.dlpi_name) };
+        let unnamed = CStr::from_bytes_with_nul(b"\0").unwrap();
+        let expected = info.dlpi_phdr as usize;
+        let phdr = |name| {
+            unsafe { program_headers(info.dlpi_addr as usize, name) }
+                .map_or(0, |(phdr, _): (*const Phdr, u16)| phdr as usize)
+        };
+        found.push((
+            name.to_string_lossy().into_owned(),
+            expected,
+            phdr(name),
+            phdr(unnamed),
+        ));
+        0
+    }
+
+    /// Checks `program_headers` agrees with `dl_iterate_phdr` for this test binary's objects,
+    /// even if every one of them is unnamed, as only the main executable should be.
+    #[test]
+    fn program_headers_match() {
+        let mut found: Vec<(String, usize, usize, usize)> = Vec::new();
+        unsafe {
+            libc::dl_iterate_phdr(Some(cb), &mut found as *mut _ as *mut libc::c_void);
+        }
+        assert!(found.len() > 1);
+        for (name, expected, actual, actual_unnamed) in found {
+            assert_eq!(expected, actual, "name={name:?}");
+            assert_eq!(expected, actual_unnamed, "name={name:?} unnamed");
+        }
+    }
+}
@@ -0,0 +1,211 @@
+#ifndef PAGE_PRIMER_H
+#define PAGE_PRIMER_H
+
+/* Generated by cbindgen from capi/src/lib.rs; don't edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Flag for `page_primer_run`: `mlock` each segment.
+ */
+#define PAGE_PRIMER_MLOCK (1 << 0)
+
+/**
+ * Flag for `page_primer_run`: remap each segment into huge pages, with the hugetlb memfd
+ * strategy unless `PAGE_PRIMER_REMAP_AUTO` is also set.
+ */
+#define PAGE_PRIMER_REMAP (1 << 1)
+
+/**
+ * Flag for `page_primer_run`: try every remap strategy, for fleets with mixed kernels and
+ * configurations. Implies `PAGE_PRIMER_REMAP`.
+ */
+#define PAGE_PRIMER_REMAP_AUTO (1 << 2)
+
+/**
+ * Flag for `page_primer_run`: verify the outcome via `/proc/self/smaps`.
+ */
+#define PAGE_PRIMER_VERIFY (1 << 3)
+
+/**
+ * Flag for `page_primer_run`: print the log to stderr.
+ */
+#define PAGE_PRIMER_LOG_STDERR (1 << 4)
+
+/**
+ * Flag for `page_primer_run`: lock pages only once touched, via `mlock2(MLOCK_ONFAULT)` or, with
+ * `PAGE_PRIMER_MLOCKALL`, `MCL_ONFAULT`. Implies `PAGE_PRIMER_MLOCK`.
+ */
+#define PAGE_PRIMER_MLOCK_ONFAULT (1 << 5)
+
+/**
+ * Flag for `page_primer_run`: lock the whole process via `mlockall(MCL_CURRENT | MCL_FUTURE)`.
+ * Implies `PAGE_PRIMER_MLOCK`.
+ */
+#define PAGE_PRIMER_MLOCKALL (1 << 6)
+
+/**
+ * The outcome of `page_primer_run`.
+ */
+typedef enum PagePrimerStatus {
+  /**
+   * Priming ran; see the report for per-segment results.
+   */
+  PAGE_PRIMER_STATUS_OK = 0,
+  /**
+   * Skipped because other threads were running.
+   */
+  PAGE_PRIMER_STATUS_THREADS_RUNNING = 1,
+  /**
+   * Skipped because the thread count couldn't be determined.
+   */
+  PAGE_PRIMER_STATUS_THREAD_COUNT_UNAVAILABLE = 2,
+  /**
+   * Skipped because no requested operation was available.
+   */
+  PAGE_PRIMER_STATUS_NOTHING_TO_DO = 3,
+  /**
+   * Skipped because no objects were loaded since the last run.
+   */
+  PAGE_PRIMER_STATUS_NO_NEW_OBJECTS = 4,
+  /**
+   * Skipped for another reason; see the report's text.
+   */
+  PAGE_PRIMER_STATUS_SKIPPED = 5,
+  /**
+   * `flags` contained an unknown bit.
+   */
+  PAGE_PRIMER_STATUS_INVALID_FLAGS = -1,
+  /**
+   * An internal error (a Rust panic) stopped priming partway; any report is null.
+   */
+  PAGE_PRIMER_STATUS_INTERNAL_ERROR = -2,
+} PagePrimerStatus;
+
+/**
+ * The results of `page_primer_run`, freed with `page_primer_report_free`.
+ */
+typedef struct PagePrimerReport PagePrimerReport;
+
+/**
+ * The results for a single ELF `PT_LOAD` segment.
+ */
+typedef struct PagePrimerSegment {
+  /**
+   * The index of the owning object; see `page_primer_report_object_path`.
+   */
+  size_t object_index;
+  /**
+   * The ELF `p_flags` (`PF_R`, `PF_W`, `PF_X`).
+   */
+  uint32_t flags;
+  /**
+   * The virtual address range, not rounded to page boundaries.
+   */
+  size_t start;
+  size_t end;
+  /**
+   * The remapped range, which is aligned to huge pages, or 0-0 if not remapped.
+   */
+  size_t remapped_start;
+  size_t remapped_end;
+  /**
+   * 0 if locked, -1 if locking wasn't attempted, or the `errno` value of the failure.
+   */
+  int32_t mlock_errno;
+} PagePrimerSegment;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Primes the process's memory as requested by `flags`, a combination of `PAGE_PRIMER_*` flags.
+ *
+ * Call this near the top of `main`, before starting any threads. If `out_report` is non-null,
+ * it receives a report to be freed with `page_primer_report_free`, even if priming was skipped,
+ * or null on `InternalError`.
+ *
+ * # Safety
+ *
+ * `out_report` must be null or valid for writes.
+ */
+enum PagePrimerStatus page_primer_run(uint32_t flags, struct PagePrimerReport **out_report);
+
+/**
+ * Frees a report returned by `page_primer_run`. Does nothing if `report` is null.
+ *
+ * # Safety
+ *
+ * `report` must be null or returned by `page_primer_run` and not yet freed.
+ */
+void page_primer_report_free(struct PagePrimerReport *report);
+
+/**
+ * Returns a human-readable summary of the report, valid until the report is freed, or null on
+ * an internal error.
+ *
+ * # Safety
+ *
+ * `report` must be a valid report.
+ */
+const char *page_primer_report_text(const struct PagePrimerReport *report);
+
+/**
+ * Returns the number of objects visited.
+ *
+ * # Safety
+ *
+ * `report` must be a valid report.
+ */
+size_t page_primer_report_object_count(const struct PagePrimerReport *report);
+
+/**
+ * Returns the path of object `i`, valid until the report is freed, or null if out of range.
+ *
+ * # Safety
+ *
+ * `report` must be a valid report.
+ */
+const char *page_primer_report_object_path(const struct PagePrimerReport *report, size_t i);
+
+/**
+ * Returns the number of segments visited.
+ *
+ * # Safety
+ *
+ * `report` must be a valid report.
+ */
+size_t page_primer_report_segment_count(const struct PagePrimerReport *report);
+
+/**
+ * Fills `out` with the results for segment `i`, returning false if out of range or on an
+ * internal error.
+ *
+ * # Safety
+ *
+ * `report` must be a valid report, and `out` must be valid for writes.
+ */
+bool page_primer_report_segment(const struct PagePrimerReport *report,
+                                size_t i,
+                                struct PagePrimerSegment *out);
+
+/**
+ * Returns why remapping segment `i` failed, valid until the report is freed, or null if it
+ * succeeded, wasn't attempted, or `i` is out of range.
+ *
+ * # Safety
+ *
+ * `report` must be a valid report.
+ */
+const char *page_primer_report_segment_remap_error(const struct PagePrimerReport *report, size_t i);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* PAGE_PRIMER_H */
@@ -0,0 +1,354 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! C ABI for `page-primer`, for C and C++ programs which call it from their `main`.
+//!
+//! The declarations are in `include/page_primer.h`, generated by `cbindgen` from this file.
+//! Link with `libpage_primer_capi.a` (see `page-primer.pc.in`).
+
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+
+/// Flag for `page_primer_run`: `mlock` each segment.
+pub const PAGE_PRIMER_MLOCK: u32 = 1 << 0;
+
+/// Flag for `page_primer_run`: remap each segment into huge pages, with the hugetlb memfd
+/// strategy unless `PAGE_PRIMER_REMAP_AUTO` is also set.
+pub const PAGE_PRIMER_REMAP: u32 = 1 << 1;
+
+/// Flag for `page_primer_run`: try every remap strategy, for fleets with mixed kernels and
+/// configurations. Implies `PAGE_PRIMER_REMAP`.
+pub const PAGE_PRIMER_REMAP_AUTO: u32 = 1 << 2;
+
+/// Flag for `page_primer_run`: verify the outcome via `/proc/self/smaps`.
+pub const PAGE_PRIMER_VERIFY: u32 = 1 << 3;
+
+/// Flag for `page_primer_run`: print the log to stderr.
+pub const PAGE_PRIMER_LOG_STDERR: u32 = 1 << 4;
+
+/// Flag for `page_primer_run`: lock pages only once touched, via `mlock2(MLOCK_ONFAULT)` or, with
+/// `PAGE_PRIMER_MLOCKALL`, `MCL_ONFAULT`. Implies `PAGE_PRIMER_MLOCK`.
+pub const PAGE_PRIMER_MLOCK_ONFAULT: u32 = 1 << 5;
+
+/// Flag for `page_primer_run`: lock the whole process via `mlockall(MCL_CURRENT | MCL_FUTURE)`.
+/// Implies `PAGE_PRIMER_MLOCK`.
+pub const PAGE_PRIMER_MLOCKALL: u32 = 1 << 6;
+
+/// The outcome of `page_primer_run`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PagePrimerStatus {
+    /// Priming ran; see the report for per-segment results.
+    Ok = 0,
+
+    /// Skipped because other threads were running.
+    ThreadsRunning = 1,
+
+    /// Skipped because the thread count couldn't be determined.
+    ThreadCountUnavailable = 2,
+
+    /// Skipped because no requested operation was available.
+    NothingToDo = 3,
+
+    /// Skipped because no objects were loaded since the last run.
+    NoNewObjects = 4,
+
+    /// Skipped for another reason; see the report's text.
+    Skipped = 5,
+
+    /// `flags` contained an unknown bit.
+    InvalidFlags = -1,
+
+    /// An internal error (a Rust panic) stopped priming partway; any report is null.
+    InternalError = -2,
+}
+
+/// The results of `page_primer_run`, freed with `page_primer_report_free`.
+pub struct PagePrimerReport {
+    report: page_primer::Report,
+    text: CString,
+    object_paths: Vec<CString>,
+    remap_errors: Vec<Option<CString>>,
+}
+
+/// The results for a single ELF `PT_LOAD` segment.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PagePrimerSegment {
+    /// The index of the owning object; see `page_primer_report_object_path`.
+    pub object_index: usize,
+
+    /// The ELF `p_flags` (`PF_R`, `PF_W`, `PF_X`).
+    pub flags: u32,
+
+    /// The virtual address range, not rounded to page boundaries.
+    pub start: usize,
+    pub end: usize,
+
+    /// The remapped range, which is aligned to huge pages, or 0-0 if not remapped.
+    pub remapped_start: usize,
+    pub remapped_end: usize,
+
+    /// 0 if locked, -1 if locking wasn't attempted, or the `errno` value of the failure.
+    pub mlock_errno: i32,
+}
+
+/// Runs `f`, returning `fallback` rather than unwinding across the C ABI if it panics.
+fn catch<T>(fallback: T, f: impl FnOnce() -> T) -> T {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(fallback)
+}
+
+fn to_cstring(s: impl Into<Vec<u8>>) -> CString {
+    let mut s = s.into();
+    s.retain(|&b| b != 0);
+    CString::new(s).expect("NULs removed")
+}
+
+/// Primes the process's memory as requested by `flags`, a combination of `PAGE_PRIMER_*` flags.
+///
+/// Call this near the top of `main`, before starting any threads. If `out_report` is non-null,
+/// it receives a report to be freed with `page_primer_report_free`, even if priming was skipped,
+/// or null on `InternalError`.
+///
+/// # Safety
+///
+/// `out_report` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn page_primer_run(
+    flags: u32,
+    out_report: *mut *mut PagePrimerReport,
+) -> PagePrimerStatus {
+    if !out_report.is_null() {
+        unsafe { *out_report = std::ptr::null_mut() };
+    }
+    catch(PagePrimerStatus::InternalError, || unsafe {
+        run(flags, out_report)
+    })
+}
+
+unsafe fn run(flags: u32, out_report: *mut *mut PagePrimerReport) -> PagePrimerStatus {
+    const ALL: u32 = PAGE_PRIMER_MLOCK
+        | PAGE_PRIMER_REMAP
+        | PAGE_PRIMER_REMAP_AUTO
+        | PAGE_PRIMER_VERIFY
+        | PAGE_PRIMER_LOG_STDERR
+        | PAGE_PRIMER_MLOCK_ONFAULT
+        | PAGE_PRIMER_MLOCKALL;
+    if (flags & !ALL) != 0 {
+        return PagePrimerStatus::InvalidFlags;
+    }
+    let lock_mode = match (
+        (flags & PAGE_PRIMER_MLOCKALL) != 0,
+        (flags & PAGE_PRIMER_MLOCK_ONFAULT) != 0,
+    ) {
+        (false, false) => page_primer::LockMode::Populate,
+        (false, true) => page_primer::LockMode::OnFault,
+        (true, false) => page_primer::LockMode::All,
+        (true, true) => page_primer::LockMode::AllOnFault,
+    };
+    let mut options = page_primer::prime()
+        .mlock(
+            (flags & (PAGE_PRIMER_MLOCK | PAGE_PRIMER_MLOCK_ONFAULT | PAGE_PRIMER_MLOCKALL)) != 0,
+        )
+        .lock_mode(lock_mode)
+        .remap((flags & (PAGE_PRIMER_REMAP | PAGE_PRIMER_REMAP_AUTO)) != 0)
+        .verify((flags & PAGE_PRIMER_VERIFY) != 0);
+    if (flags & PAGE_PRIMER_REMAP_AUTO) != 0 {
+        options = options.remap_auto();
+    }
+    let out = options.run();
+    if (flags & PAGE_PRIMER_LOG_STDERR) != 0 {
+        out.eprint();
+    }
+    let report = out.into_report();
+    let status = match report.skipped {
+        None => PagePrimerStatus::Ok,
+        Some(page_primer::Skipped::ThreadsRunning(_)) => PagePrimerStatus::ThreadsRunning,
+        Some(page_primer::Skipped::ThreadCountUnavailable) => {
+            PagePrimerStatus::ThreadCountUnavailable
+        }
+        Some(page_primer::Skipped::NothingToDo) => PagePrimerStatus::NothingToDo,
+        Some(page_primer::Skipped::NoNewObjects) => PagePrimerStatus::NoNewObjects,
+        Some(_) => PagePrimerStatus::Skipped,
+    };
+    if !out_report.is_null() {
+        let report = Box::new(PagePrimerReport {
+            text: to_cstring(report.to_string()),
+            object_paths: report
+                .objects
+                .iter()
+                .map(|o| to_cstring(o.path.as_os_str().as_bytes()))
+                .collect(),
+            remap_errors: report
+                .segments
+                .iter()
+                .map(|s| match &s.remap {
+                    Some(Err(e)) => Some(to_cstring(e.to_string())),
+                    _ => None,
+                })
+                .collect(),
+            report,
+        });
+        unsafe { *out_report = Box::into_raw(report) };
+    }
+    status
+}
+
+/// Frees a report returned by `page_primer_run`. Does nothing if `report` is null.
+///
+/// # Safety
+///
+/// `report` must be null or returned by `page_primer_run` and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn page_primer_report_free(report: *mut PagePrimerReport) {
+    if !report.is_null() {
+        catch((), || drop(unsafe { Box::from_raw(report) }));
+    }
+}
+
+/// Returns a human-readable summary of the report, valid until the report is freed, or null on
+/// an internal error.
+///
+/// # Safety
+///
+/// `report` must be a valid report.
+#[no_mangle]
+pub unsafe extern "C" fn page_primer_report_text(report: *const PagePrimerReport) -> *const c_char {
+    catch(std::ptr::null(), || unsafe { &*report }.text.as_ptr())
+}
+
+/// Returns the number of objects visited.
+///
+/// # Safety
+///
+/// `report` must be a valid report.
+#[no_mangle]
+pub unsafe extern "C" fn page_primer_report_object_count(report: *const PagePrimerReport) -> usize {
+    catch(0, || unsafe { &*report }.object_paths.len())
+}
+
+/// Returns the path of object `i`, valid until the report is freed, or null if out of range.
+///
+/// # Safety
+///
+/// `report` must be a valid report.
+#[no_mangle]
+pub unsafe extern "C" fn page_primer_report_object_path(
+    report: *const PagePrimerReport,
+    i: usize,
+) -> *const c_char {
+    catch(std::ptr::null(), || {
+        match unsafe { &*report }.object_paths.get(i) {
+            Some(p) => p.as_ptr(),
+            None => std::ptr::null(),
+        }
+    })
+}
+
+/// Returns the number of segments visited.
+///
+/// # Safety
+///
+/// `report` must be a valid report.
+#[no_mangle]
+pub unsafe extern "C" fn page_primer_report_segment_count(
+    report: *const PagePrimerReport,
+) -> usize {
+    catch(0, || unsafe { &*report }.report.segments.len())
+}
+
+/// Fills `out` with the results for segment `i`, returning false if out of range or on an
+/// internal error.
+///
+/// # Safety
+///
+/// `report` must be a valid report, and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn page_primer_report_segment(
+    report: *const PagePrimerReport,
+    i: usize,
+    out: *mut PagePrimerSegment,
+) -> bool {
+    catch(false, || unsafe { report_segment(report, i, out) })
+}
+
+unsafe fn report_segment(
+    report: *const PagePrimerReport,
+    i: usize,
+    out: *mut PagePrimerSegment,
+) -> bool {
+    let Some(s) = unsafe { &*report }.report.segments.get(i) else {
+        return false;
+    };
+    let remapped = s.remapped().cloned().unwrap_or(0..0);
+    unsafe {
+        *out = PagePrimerSegment {
+            object_index: s.object_i,
+            flags: s.flags,
+            start: s.addrs.start,
+            end: s.addrs.end,
+            remapped_start: remapped.start,
+            remapped_end: remapped.end,
+            mlock_errno: match s.mlock {
+                None => -1,
+                Some(Ok(())) => 0,
+                Some(Err(errno)) => errno,
+            },
+        }
+    };
+    true
+}
+
+/// Returns why remapping segment `i` failed, valid until the report is freed, or null if it
+/// succeeded, wasn't attempted, or `i` is out of range.
+///
+/// # Safety
+///
+/// `report` must be a valid report.
+#[no_mangle]
+pub unsafe extern "C" fn page_primer_report_segment_remap_error(
+    report: *const PagePrimerReport,
+    i: usize,
+) -> *const c_char {
+    catch(std::ptr::null(), || {
+        match unsafe { &*report }.remap_errors.get(i) {
+            Some(Some(e)) => e.as_ptr(),
+            _ => std::ptr::null(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn catch_panic() {
+        assert_eq!(catch(1, || 2), 2);
+        assert_eq!(catch(1, || panic!("boom")), 1);
+    }
+
+    /// Checks `include/page_primer.h` matches this crate. Set `PAGE_PRIMER_BLESS=1` to
+    /// regenerate it.
+    #[test]
+    fn header_up_to_date() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let config = cbindgen::Config::from_file(dir.join("cbindgen.toml")).unwrap();
+        let mut generated = Vec::new();
+        cbindgen::generate_with_config(dir, config)
+            .unwrap()
+            .write(&mut generated);
+        let path = dir.join("include/page_primer.h");
+        if std::env::var_os("PAGE_PRIMER_BLESS").is_some() {
+            std::fs::write(&path, &generated).unwrap();
+        }
+        let committed = std::fs::read(&path).unwrap_or_default();
+        assert!(
+            committed == generated,
+            "{} is stale; rerun with PAGE_PRIMER_BLESS=1",
+            path.display()
+        );
+    }
+}
@@ -0,0 +1,14 @@
+//! Primes pages via the `#[page_primer::main]` attribute, then logs the results once
+//! `env_logger` is configured.
+//!
+//! Run with `cargo run --features macros --example attribute` and the
+//! `RUST_LOG=page_primer=info` environment variable set.
+
+#[page_primer::main(mlock, remap)]
+fn main() {
+    env_logger::init();
+    if let Some(out) = page_primer::take_output() {
+        out.log();
+    }
+    log::info!("main started");
+}
@@ -19,7 +19,11 @@ fn bar() {
 
 fn main() {
     // Typically this runs right at the start of `main`. Save the result for printing later.
-    let prime_out = page_primer::prime().mlock(true).remap(true).run();
+    let prime_out = page_primer::prime()
+        .mlock(true)
+        .remap(true)
+        .verify(true)
+        .run();
 
     env_logger::init();
 
@@ -0,0 +1,218 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The `#[page_primer::main]` attribute; use it via `page-primer`'s `macros` feature.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse::Parser;
+use syn::spanned::Spanned;
+
+/// Primes pages at the very start of `main`, before any other attribute (such as
+/// `#[tokio::main]`) starts threads.
+///
+/// This composes with such attributes in either order. An `async fn main` must also have one,
+/// recognized by its name ending in `main`.
+///
+/// Arguments select the options: any of `mlock`, `remap`, `remap_auto`, and `verify`, plus
+/// optionally `hook = path::to::fn` naming a `fn(page_primer::Output)` to receive the output.
+/// Without a hook, the output is passed to `page_primer::Output::stash`; retrieve it with
+/// `page_primer::take_output` once logging is configured.
+///
+/// ```rust,ignore
+/// #[page_primer::main(mlock, remap)]
+/// #[tokio::main]
+/// async fn main() {
+///     env_logger::init();
+///     if let Some(out) = page_primer::take_output() {
+///         out.log();
+///     }
+///     // ...
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn main(args: TokenStream, item: TokenStream) -> TokenStream {
+    match expand(args.into(), item.into()) {
+        Ok(t) => t.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(
+    args: proc_macro2::TokenStream,
+    item: proc_macro2::TokenStream,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let args_span = args.span();
+    let mut options = Vec::new();
+    let mut hook: Option<syn::Path> = None;
+    syn::meta::parser(|meta| {
+        let Some(ident) = meta.path.get_ident() else {
+            return Err(meta.error("unsupported page_primer::main argument"));
+        };
+        match ident.to_string().as_str() {
+            "mlock" => options.push(quote!(.mlock(true))),
+            "remap" => options.push(quote!(.remap(true))),
+            "remap_auto" => options.push(quote!(.remap(true).remap_auto())),
+            "verify" => options.push(quote!(.verify(true))),
+            "hook" => hook = Some(meta.value()?.parse()?),
+            _ => return Err(meta.error("unsupported page_primer::main argument")),
+        }
+        Ok(())
+    })
+    .parse2(args)?;
+    if options.is_empty() {
+        return Err(syn::Error::new(
+            args_span,
+            "expected at least one of mlock, remap, remap_auto, verify",
+        ));
+    }
+
+    let inner: syn::ItemFn = syn::parse2(item)?;
+    if inner.sig.ident != "main" {
+        return Err(syn::Error::new(
+            inner.sig.ident.span(),
+            "page_primer::main must be applied to fn main",
+        ));
+    }
+    // Runtime attributes are conventionally named `main`, e.g. `#[tokio::main]` or
+    // `#[async_std::main]`.
+    let has_runtime = inner
+        .attrs
+        .iter()
+        .any(|a| a.path().segments.last().is_some_and(|s| s.ident == "main"));
+    if inner.sig.asyncness.is_some() && !has_runtime {
+        return Err(syn::Error::new(
+            inner.sig.fn_token.span(),
+            "async fn main needs a runtime attribute such as #[tokio::main] after \
+             #[page_primer::main]",
+        ));
+    }
+
+    // The wrapper has the signature `main` will have once the remaining attributes (if any) make
+    // it synchronous.
+    let mut outer_sig = inner.sig.clone();
+    outer_sig.asyncness = None;
+    let vis = &inner.vis;
+    let hook = match hook {
+        Some(h) => quote!(#h),
+        None => quote!(::page_primer::Output::stash),
+    };
+    Ok(quote! {
+        #vis #outer_sig {
+            #hook(::page_primer::prime() #(#options)* .run());
+            #inner
+            main()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_err(args: proc_macro2::TokenStream, item: proc_macro2::TokenStream) -> String {
+        expand(args, item).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn argument_errors() {
+        let item = quote!(
+            fn main() {}
+        );
+        assert_eq!(
+            expand_err(quote!(), item.clone()),
+            "expected at least one of mlock, remap, remap_auto, verify"
+        );
+        assert_eq!(
+            expand_err(quote!(mlock, bogus), item.clone()),
+            "unsupported page_primer::main argument"
+        );
+        assert_eq!(
+            expand_err(quote!(a::b), item.clone()),
+            "unsupported page_primer::main argument"
+        );
+        assert!(expand(quote!(mlock, hook), item.clone()).is_err());
+        assert_eq!(
+            expand_err(
+                quote!(mlock),
+                quote!(
+                    fn other() {}
+                )
+            ),
+            "page_primer::main must be applied to fn main"
+        );
+    }
+
+    #[test]
+    fn async_without_runtime() {
+        assert_eq!(
+            expand_err(
+                quote!(mlock),
+                quote!(
+                    async fn main() {}
+                )
+            ),
+            "async fn main needs a runtime attribute such as #[tokio::main] after \
+             #[page_primer::main]"
+        );
+        assert_eq!(
+            expand_err(
+                quote!(mlock),
+                quote!(
+                    /// Docs.
+                    #[allow(unused)]
+                    async fn main() {}
+                )
+            ),
+            "async fn main needs a runtime attribute such as #[tokio::main] after \
+             #[page_primer::main]"
+        );
+    }
+
+    /// Checks `#[page_primer::main]` above `#[tokio::main]`, which it sees unexpanded.
+    #[test]
+    fn before_runtime() {
+        let out = expand(
+            quote!(mlock, remap, hook = my::hook),
+            quote!(
+                #[tokio::main]
+                async fn main() {}
+            ),
+        )
+        .unwrap();
+        let expected = quote! {
+            fn main() {
+                my::hook(::page_primer::prime().mlock(true).remap(true).run());
+                #[tokio::main]
+                async fn main() {}
+                main()
+            }
+        };
+        assert_eq!(out.to_string(), expected.to_string());
+    }
+
+    /// Checks `#[page_primer::main]` below `#[tokio::main]`, which it sees already expanded into
+    /// a synchronous `main` that starts the runtime.
+    #[test]
+    fn after_runtime() {
+        let inner = quote! {
+            fn main() {
+                let body = async {};
+                tokio::runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .build()
+                    .expect("Failed building the Runtime")
+                    .block_on(body)
+            }
+        };
+        let out = expand(quote!(verify), inner.clone()).unwrap();
+        let expected = quote! {
+            fn main() {
+                ::page_primer::Output::stash(::page_primer::prime().verify(true).run());
+                #inner
+                main()
+            }
+        };
+        assert_eq!(out.to_string(), expected.to_string());
+    }
+}
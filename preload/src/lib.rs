@@ -0,0 +1,50 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A library which primes any dynamically linked program, e.g. C++ or Go with cgo, via
+//! `LD_PRELOAD=/path/to/libpage_primer_preload.so`.
+//!
+//! An ELF constructor runs [`page_primer::prime`] before `main` and normally before any threads
+//! exist. It's configured by the `PAGE_PRIMER` environment variable, a comma-separated list of
+//! items described in [`page_primer::Spec::parse`], e.g. `PAGE_PRIMER=mlock,remap=auto`.
+//! Unless `quiet` is given, the log is printed to stderr.
+//!
+//! If `PAGE_PRIMER` is unset or empty, nothing happens.
+
+use page_primer::Spec;
+
+extern "C" fn init(
+    _argc: libc::c_int,
+    _argv: *const *const libc::c_char,
+    _envp: *const *const libc::c_char,
+) {
+    // Unwinding into the dynamic loader is undefined behavior, so contain any panic.
+    if std::panic::catch_unwind(prime).is_err() {
+        eprintln!("page-primer: panicked while priming; continuing without it");
+    }
+}
+
+/// Primes as configured by `PAGE_PRIMER`.
+fn prime() {
+    let spec = match std::env::var("PAGE_PRIMER") {
+        Ok(s) if !s.is_empty() => s,
+        _ => return,
+    };
+    let config = Spec::parse(&spec);
+    if !config.quiet {
+        for w in &config.warnings {
+            eprintln!("page-primer: ignoring PAGE_PRIMER {w}");
+        }
+    }
+    let out = config.options.run();
+    if !config.quiet {
+        out.eprint();
+    }
+}
+
+/// Runs [`init`] as an ELF constructor, after this library's dependencies (such as libc) are
+/// initialized but before the program's own constructors and `main`.
+#[used]
+#[link_section = ".init_array"]
+static INIT: extern "C" fn(libc::c_int, *const *const libc::c_char, *const *const libc::c_char) =
+    init;
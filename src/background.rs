@@ -0,0 +1,237 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Locking on a background thread; see [`crate::Options::run_background`].
+
+use crate::Output;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+/// The progress of background locking, in bytes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes locked (or attempted) so far.
+    pub done: usize,
+
+    /// Bytes to lock in total.
+    pub total: usize,
+}
+
+struct Shared {
+    done: AtomicUsize,
+    total: usize,
+
+    /// Set once locking is done, even after the output is taken.
+    finished: AtomicBool,
+
+    /// The final output, or the panic payload if locking panicked, until taken by
+    /// [`Background::join`] or polling.
+    output: Mutex<Option<std::thread::Result<Output>>>,
+    waker: Mutex<Option<Waker>>,
+}
+
+/// A handle to locking in progress on a background thread, from
+/// [`crate::Options::run_background`].
+///
+/// Await it or call [`Background::join`] for the final [`Output`]. Dropping it lets locking
+/// continue unobserved.
+#[must_use = "Background does nothing unless joined or awaited to get the Output"]
+pub struct Background {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Background {
+    /// Returns a handle which is already finished with `output`.
+    pub(crate) fn finished(output: Output) -> Self {
+        Background {
+            shared: Arc::new(Shared {
+                done: AtomicUsize::new(0),
+                total: 0,
+                finished: AtomicBool::new(true),
+                output: Mutex::new(Some(Ok(output))),
+                waker: Mutex::new(None),
+            }),
+            thread: None,
+        }
+    }
+
+    /// Runs `lock` on a background thread, or on this thread if one can't be spawned.
+    ///
+    /// `lock` should add to the given counter as it locks each segment of `output`.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn spawn(
+        output: Output,
+        lock: impl FnOnce(&mut Output, &AtomicUsize) + Send + 'static,
+    ) -> Self {
+        let base_page_size = output.report.base_page_size;
+        let shared = Arc::new(Shared {
+            done: AtomicUsize::new(0),
+            total: output
+                .report
+                .segments
+                .iter()
+                .filter(|s| s.lock_wanted())
+                .map(|s| s.lock_range(base_page_size).len())
+                .sum(),
+            finished: AtomicBool::new(false),
+            output: Mutex::new(None),
+            waker: Mutex::new(None),
+        });
+        let shared2 = shared.clone();
+        let work = move || {
+            // Catch any panic, so awaiting completes (by panicking) rather than hanging.
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut output = output;
+                lock(&mut output, &shared2.done);
+                output
+            }));
+            *shared2
+                .output
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(result);
+            shared2.finished.store(true, Ordering::Release);
+            let waker = shared2
+                .waker
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take();
+            if let Some(w) = waker {
+                w.wake();
+            }
+        };
+
+        // `Builder::spawn` consumes the closure even on failure, so share it to retry inline.
+        let work = Arc::new(Mutex::new(Some(work)));
+        let work2 = work.clone();
+        let thread = std::thread::Builder::new()
+            .name("page-primer-lock".to_owned())
+            .spawn(move || {
+                if let Some(w) = work2.lock().unwrap_or_else(PoisonError::into_inner).take() {
+                    w()
+                }
+            })
+            .ok();
+        if thread.is_none() {
+            if let Some(w) = work.lock().unwrap_or_else(PoisonError::into_inner).take() {
+                w()
+            }
+        }
+        Background { shared, thread }
+    }
+
+    /// Returns the progress so far.
+    pub fn progress(&self) -> Progress {
+        Progress {
+            done: self.shared.done.load(Ordering::Relaxed),
+            total: self.shared.total,
+        }
+    }
+
+    /// Returns true iff locking is done.
+    pub fn is_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Acquire)
+    }
+
+    /// Waits for locking to finish, returning the final output.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the output was already taken by polling this as a [`Future`] to completion, or
+    /// if locking panicked, with the same payload.
+    pub fn join(mut self) -> Output {
+        if let Some(t) = self.thread.take() {
+            if let Err(e) = t.join() {
+                std::panic::resume_unwind(e);
+            }
+        }
+        self.take()
+    }
+
+    /// Takes the output, resuming any panic from locking.
+    fn take(&self) -> Output {
+        let result = self
+            .shared
+            .output
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .expect("output not yet taken");
+        match result {
+            Ok(output) => output,
+            Err(e) => std::panic::resume_unwind(e),
+        }
+    }
+}
+
+/// Completes with the final output once locking is done.
+///
+/// If locking panicked, polling resumes the panic once it's done. As usual for futures, polling
+/// again after completion panics.
+impl Future for Background {
+    type Output = Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Output> {
+        // Register before checking, so completion in between isn't missed.
+        *self
+            .shared
+            .waker
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(cx.waker().clone());
+        match self.is_finished() {
+            true => Poll::Ready(self.take()),
+            false => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoopWaker;
+
+    impl std::task::Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[test]
+    fn finished_after_poll() {
+        let mut bg = Background::finished(Output {
+            log: Vec::new(),
+            report: Default::default(),
+        });
+        assert!(bg.is_finished());
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut bg).poll(&mut cx).is_ready());
+        assert!(bg.is_finished());
+    }
+
+    #[test]
+    fn lock_panic_completes_future() {
+        let mut bg = Background::spawn(
+            Output {
+                log: Vec::new(),
+                report: Default::default(),
+            },
+            |_, _| panic!("lock failed"),
+        );
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        let e = std::panic::catch_unwind(AssertUnwindSafe(|| loop {
+            if Pin::new(&mut bg).poll(&mut cx).is_ready() {
+                break;
+            }
+            std::thread::yield_now();
+        }))
+        .unwrap_err();
+        assert_eq!(e.downcast_ref::<&str>(), Some(&"lock failed"));
+        assert!(bg.is_finished());
+    }
+}
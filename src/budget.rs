@@ -0,0 +1,144 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Ranking segments to fit within a [`Budget`].
+
+use crate::report::{ObjectReport, PF_W, PF_X};
+use std::ops::Range;
+
+/// A limit on the memory priming may pin, for [`crate::Options::budget`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Budget {
+    /// At most this many bytes.
+    Bytes(usize),
+
+    /// At most this percentage of the cgroup v2 `memory.max`, or of the smallest limit among its
+    /// ancestors. If there's no limit, there's no budget. Values above 100 are treated as 100.
+    CgroupPercent(u8),
+}
+
+/// Returns the segment's rank: lower is primed first.
+///
+/// The main executable's text comes first, then its read-only data, then shared objects' text
+/// and read-only data, then writable data. Within each tier, shared objects matching an earlier
+/// entry of `prefer` come first, then the rest in load order.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn rank(
+    objects: &[ObjectReport],
+    prefer: &[String],
+    object_i: usize,
+    flags: u32,
+) -> (u8, usize, usize) {
+    let is_main = objects[object_i].is_main;
+    let tier = match ((flags & PF_W) != 0, (flags & PF_X) != 0, is_main) {
+        (false, true, true) => 0,
+        (false, false, true) => 1,
+        (false, true, false) => 2,
+        (false, false, false) => 3,
+        (true, _, true) => 4,
+        (true, _, false) => 5,
+    };
+    let preference = match is_main {
+        true => 0,
+        false => {
+            let name = objects[object_i].path.file_name().unwrap_or_default();
+            let name = name.to_string_lossy();
+            prefer
+                .iter()
+                .position(|p| name.starts_with(p.as_str()))
+                .unwrap_or(prefer.len())
+        }
+    };
+    (tier, preference, object_i)
+}
+
+/// Chooses segments greedily in rank order, skipping any which would exceed `budget`.
+///
+/// `segments` are `(object_i, flags, addrs)`; `cost` estimates the bytes priming one would pin.
+/// Returns the chosen segments' start addresses, sorted.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn select(
+    objects: &[ObjectReport],
+    segments: &[(usize, u32, Range<usize>)],
+    prefer: &[String],
+    budget: usize,
+    cost: impl Fn(u32, &Range<usize>) -> usize,
+) -> Vec<usize> {
+    let mut ranked: Vec<_> = segments.iter().collect();
+    ranked.sort_by_key(|&&(object_i, flags, ref addrs)| {
+        (rank(objects, prefer, object_i, flags), addrs.start)
+    });
+    let mut remaining = budget;
+    let mut chosen: Vec<usize> = ranked
+        .into_iter()
+        .filter(|&&(_, flags, ref addrs)| {
+            let c = cost(flags, addrs);
+            let fits = c <= remaining;
+            if fits {
+                remaining -= c;
+            }
+            fits
+        })
+        .map(|(_, _, addrs)| addrs.start)
+        .collect();
+    chosen.sort_unstable();
+    chosen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::PF_R;
+    use std::path::PathBuf;
+
+    #[test]
+    fn select_by_rank() {
+        let mut objects: Vec<_> = ["/bin/foo", "/lib/libz.so.1", "/lib/libc.so.6"]
+            .iter()
+            .enumerate()
+            .map(|(index, p)| ObjectReport {
+                index,
+                path: PathBuf::from(p),
+                load_bias: 0,
+                is_main: index == 0,
+            })
+            .collect();
+        let segments = vec![
+            (0, PF_R, 0x1000..0x2000),
+            (0, PF_R | PF_X, 0x2000..0x4000),
+            (0, PF_R | PF_W, 0x4000..0x5000),
+            (1, PF_R | PF_X, 0x10000..0x12000),
+            (2, PF_R | PF_X, 0x20000..0x22000),
+            (2, PF_R, 0x22000..0x23000),
+        ];
+        let cost = |_, addrs: &Range<usize>| addrs.len();
+        let prefer = ["libc.so".to_owned()];
+
+        // Main text and rodata, then libc's text; libz's text doesn't fit but libc's rodata does.
+        assert_eq!(
+            select(&objects, &segments, &prefer, 0x6000, cost),
+            vec![0x1000, 0x2000, 0x20000, 0x22000]
+        );
+
+        // Without a preference, libz comes first in load order.
+        assert_eq!(
+            select(&objects, &segments, &[], 0x6000, cost),
+            vec![0x1000, 0x2000, 0x10000, 0x22000]
+        );
+
+        // The main executable is found by its flag, not its position.
+        objects.rotate_left(1);
+        for (index, o) in objects.iter_mut().enumerate() {
+            o.index = index;
+        }
+        let segments: Vec<_> = segments
+            .iter()
+            .map(|(object_i, flags, addrs)| ((object_i + 2) % 3, *flags, addrs.clone()))
+            .collect();
+        assert_eq!(
+            select(&objects, &segments, &prefer, 0x6000, cost),
+            vec![0x1000, 0x2000, 0x20000, 0x22000]
+        );
+    }
+}
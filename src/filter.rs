@@ -0,0 +1,238 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Per-segment filtering; see [`crate::Options::filter`].
+
+use crate::report::{PF_W, PF_X};
+use std::fmt::Debug;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What to do with a segment, as decided by a [`Filter`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Action {
+    /// Whether to lock the segment, if [`crate::Options::mlock`] is set.
+    pub mlock: bool,
+
+    /// Whether to remap the segment, if [`crate::Options::remap`] is set.
+    pub remap: bool,
+}
+
+impl Action {
+    /// Locks and remaps as requested by [`crate::Options`]; the default without filters.
+    pub const BOTH: Action = Action {
+        mlock: true,
+        remap: true,
+    };
+
+    /// Locks but doesn't remap.
+    pub const MLOCK_ONLY: Action = Action {
+        mlock: true,
+        remap: false,
+    };
+
+    /// Remaps but doesn't lock.
+    pub const REMAP_ONLY: Action = Action {
+        mlock: false,
+        remap: true,
+    };
+
+    /// Leaves the segment alone.
+    pub const NEITHER: Action = Action {
+        mlock: false,
+        remap: false,
+    };
+
+    /// Returns the operations both `self` and `other` allow.
+    #[inline]
+    pub fn and(self, other: Action) -> Action {
+        Action {
+            mlock: self.mlock && other.mlock,
+            remap: self.remap && other.remap,
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match (self.mlock, self.remap) {
+            (true, true) => "mlock+remap",
+            (true, false) => "mlock",
+            (false, true) => "remap",
+            (false, false) => "none",
+        })
+    }
+}
+
+/// An object passed to a [`Filter`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ObjectInfo<'a> {
+    /// The object's path, as in [`crate::ObjectReport::path`].
+    pub path: &'a Path,
+
+    /// True iff this is the main executable.
+    pub is_main: bool,
+}
+
+/// A `PT_LOAD` segment passed to a [`Filter`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct SegmentInfo {
+    /// The ELF `p_flags` (`PF_R`, `PF_W`, `PF_X`).
+    pub flags: u32,
+
+    /// The virtual address range, not rounded to page boundaries.
+    pub addrs: Range<usize>,
+}
+
+impl SegmentInfo {
+    /// Returns true iff the segment has `PF_W`.
+    #[inline]
+    pub fn is_writable(&self) -> bool {
+        (self.flags & PF_W) != 0
+    }
+
+    /// Returns true iff the segment has `PF_X`.
+    #[inline]
+    pub fn is_executable(&self) -> bool {
+        (self.flags & PF_X) != 0
+    }
+}
+
+type FilterFn = dyn Fn(&ObjectInfo<'_>, &SegmentInfo) -> Action + Send + Sync;
+
+/// Decides what to do with each segment, for [`crate::Options::filter`].
+///
+/// Filters are evaluated before priming starts, so they may allocate.
+///
+/// Filters compare equal only to clones of themselves, as closures can't be compared. Thus
+/// separately constructed filters are unequal even if they'd make the same decisions, as are
+/// [`crate::Options`] holding them.
+#[derive(Clone)]
+pub struct Filter(Arc<FilterFn>);
+
+impl Filter {
+    /// Returns a filter which calls `f`.
+    pub fn new(
+        f: impl Fn(&ObjectInfo<'_>, &SegmentInfo) -> Action + Send + Sync + 'static,
+    ) -> Self {
+        Filter(Arc::new(f))
+    }
+
+    /// Primes only objects whose path matches `pattern`, where `*` matches any sequence of bytes
+    /// (including `/`) and `?` matches any single byte. E.g., `*/libfoo.so*`.
+    ///
+    /// For regular expressions, use [`Filter::new`] with a regex crate.
+    pub fn path_glob(pattern: impl Into<String>) -> Self {
+        let pattern = pattern.into();
+        Filter::new(move |o, _| {
+            match glob_match(pattern.as_bytes(), o.path.as_os_str().as_encoded_bytes()) {
+                true => Action::BOTH,
+                false => Action::NEITHER,
+            }
+        })
+    }
+
+    /// Primes only the main executable, excluding shared objects, the vDSO, and the dynamic
+    /// loader.
+    pub fn main_only() -> Self {
+        Filter::new(|o, _| match o.is_main {
+            true => Action::BOTH,
+            false => Action::NEITHER,
+        })
+    }
+
+    /// Primes only executable (`PF_X`) segments.
+    pub fn executable_only() -> Self {
+        Filter::new(|_, s| match s.is_executable() {
+            true => Action::BOTH,
+            false => Action::NEITHER,
+        })
+    }
+
+    /// Skips writable (`PF_W`) segments.
+    pub fn skip_writable() -> Self {
+        Filter::new(|_, s| match s.is_writable() {
+            true => Action::NEITHER,
+            false => Action::BOTH,
+        })
+    }
+
+    pub(crate) fn apply(&self, object: &ObjectInfo<'_>, segment: &SegmentInfo) -> Action {
+        (self.0)(object, segment)
+    }
+}
+
+impl Debug for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Filter(..)")
+    }
+}
+
+/// Compares by identity; see [`Filter`].
+impl PartialEq for Filter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Filter {}
+
+/// Returns true iff `text` matches the glob `pattern`; see [`Filter::path_glob`].
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    // Iterative matching with backtracking to the most recent `*`.
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((sp, st)) => {
+                    p = sp + 1;
+                    t = st + 1;
+                    star = Some((sp, st + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(
+            b"*/libc.so*",
+            b"/lib/x86_64-linux-gnu/libc.so.6"
+        ));
+        assert!(glob_match(b"/bin/fo?", b"/bin/foo"));
+        assert!(glob_match(b"*", b""));
+        assert!(!glob_match(b"*/libc.so*", b"/lib/libcrypto.so.3"));
+        assert!(!glob_match(b"/bin/fo?", b"/bin/fooo"));
+    }
+
+    #[test]
+    fn eq_is_identity() {
+        let f = Filter::skip_writable();
+        assert_eq!(f, f.clone());
+        assert_ne!(f, Filter::skip_writable());
+        assert_ne!(
+            crate::prime().filter(Filter::skip_writable()),
+            crate::prime().filter(Filter::skip_writable())
+        );
+    }
+}
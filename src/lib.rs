@@ -3,50 +3,498 @@
 
 #![doc = include_str!("../README.md")]
 
+mod background;
+mod budget;
+mod filter;
 #[cfg(target_os = "linux")]
 mod linux;
+mod plan;
+mod report;
+mod spec;
+mod strict;
+
+pub use background::{Background, Progress};
+pub use budget::Budget;
+pub use filter::{Action, Filter, ObjectInfo, SegmentInfo};
+#[cfg(feature = "macros")]
+pub use page_primer_macros::main;
+pub use plan::{Plan, SegmentPlan};
+pub use report::{
+    Coverage, Fallbacks, HugeError, Memlock, ObjectReport, Report, SegmentReport, Skipped,
+};
+pub use spec::Spec;
+pub use strict::{PrimeError, Strict, Violation};
+
+use std::sync::{Mutex, PoisonError};
+
 /// The options for priming.
 ///
 /// By default, *nothing* will happen; call `mlock` and/or `remap` to change this.
+///
+/// Options with [`Options::filter`]s compare equal only if their filters are clones; see
+/// [`Filter`].
 #[derive(Default, Debug, PartialEq, Eq)]
 #[must_use = "Options do nothing without Options::run"]
 pub struct Options {
     mlock: bool,
+    lock_mode: LockMode,
     remap: bool,
+    remap_strategies: Vec<RemapStrategy>,
+    hugetlb_page_size: Option<usize>,
+    verify: bool,
+    strict: Strict,
+    new_objects_only: bool,
+    stop_the_world: bool,
+    background_lock: bool,
+    budget: Option<Budget>,
+    prefer_objects: Vec<String>,
+    filters: Vec<Filter>,
+
+    #[cfg(feature = "serde")]
+    manifest: Option<std::path::PathBuf>,
 }
 
 impl Options {
     /// Sets whether `mlock` should be performed.
     #[inline]
-    #[must_use]
+    #[must_use = "Options::mlock returns the updated Options"]
     pub fn mlock(self, mlock: bool) -> Self {
         Self { mlock, ..self }
     }
 
+    /// Sets how [`Options::mlock`] locks; see [`LockMode`].
+    ///
+    /// The default is [`LockMode::Populate`].
+    #[inline]
+    #[must_use = "Options::lock_mode returns the updated Options"]
+    pub fn lock_mode(self, lock_mode: LockMode) -> Self {
+        Self { lock_mode, ..self }
+    }
+
     /// Sets whether pages should be remapped.
     #[inline]
-    #[must_use]
+    #[must_use = "Options::remap returns the updated Options"]
     pub fn remap(self, remap: bool) -> Self {
         Self { remap, ..self }
     }
 
+    /// Sets a path to which the [`Report`] will be written as JSON, e.g.
+    /// `/run/myapp/page-primer.json`.
+    ///
+    /// Failure to write is logged rather than returned.
+    #[cfg(feature = "serde")]
+    #[inline]
+    #[must_use = "Options::manifest returns the updated Options"]
+    pub fn manifest(self, path: impl Into<std::path::PathBuf>) -> Self {
+        Self {
+            manifest: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Sets how pages should be remapped; see [`RemapStrategy`].
+    ///
+    /// The default is [`RemapStrategy::HugetlbMemfd`].
+    #[inline]
+    #[must_use = "Options::remap_strategy returns the updated Options"]
+    pub fn remap_strategy(self, remap_strategy: RemapStrategy) -> Self {
+        self.remap_strategies([remap_strategy])
+    }
+
+    /// Sets an ordered list of ways pages may be remapped.
+    ///
+    /// Before touching memory, strategies the kernel definitely doesn't support (e.g.
+    /// [`RemapStrategy::HugetlbMemfd`] with no free hugetlb pages) are skipped. Then each segment
+    /// is remapped with the first strategy that succeeds for it. Duplicates are ignored.
+    #[must_use = "Options::remap_strategies returns the updated Options"]
+    pub fn remap_strategies(
+        self,
+        remap_strategies: impl IntoIterator<Item = RemapStrategy>,
+    ) -> Self {
+        let mut deduped = Vec::new();
+        for s in remap_strategies {
+            if !deduped.contains(&s) {
+                deduped.push(s);
+            }
+        }
+        Self {
+            remap_strategies: deduped,
+            ..self
+        }
+    }
+
+    /// Tries every remap strategy, in the order of [`RemapStrategy::AUTO`].
+    ///
+    /// This suits fleets with mixed kernels and configurations.
+    #[inline]
+    #[must_use = "Options::remap_auto returns the updated Options"]
+    pub fn remap_auto(self) -> Self {
+        self.remap_strategies(RemapStrategy::AUTO.iter().copied())
+    }
+
+    /// Sets a larger hugetlb page size, e.g. 1 GiB, for [`RemapStrategy::HugetlbMemfd`].
+    ///
+    /// The size must have a pool under `/sys/kernel/mm/hugepages/` with free pages; otherwise it's
+    /// ignored with a warning. The whole large pages within each segment use this size; the
+    /// remainder uses the regular huge page size, so no extra padding is mapped.
+    #[inline]
+    #[must_use = "Options::hugetlb_page_size returns the updated Options"]
+    pub fn hugetlb_page_size(self, size: usize) -> Self {
+        Self {
+            hugetlb_page_size: Some(size),
+            ..self
+        }
+    }
+
+    /// Returns the remap strategies to attempt, in order.
+    #[cfg(target_os = "linux")]
+    fn effective_remap_strategies(&self) -> &[RemapStrategy] {
+        match self.remap_strategies.is_empty() {
+            true => &[RemapStrategy::HugetlbMemfd],
+            false => &self.remap_strategies,
+        }
+    }
+
+    /// Sets a limit on the memory to lock and remap; see [`Budget`].
+    ///
+    /// Segments are ranked: the main executable's text first, then its read-only data, then shared
+    /// objects' text and read-only data (see [`Options::prefer_objects`]), then writable data.
+    /// They're chosen greedily in that order, skipping any whose cost would exceed what's left,
+    /// where a segment's cost is its size rounded out to huge pages if it may be remapped.
+    /// Segments with nothing to do, such as writable data when only remapping, cost nothing.
+    /// Skipped segments are neither locked nor remapped and are marked
+    /// [`SegmentReport::deprioritized`].
+    ///
+    /// This applies to [`Options::run`], [`Options::run_background`], and [`Options::plan`], not
+    /// [`Options::run_objects`]. It applies to each run separately: with [`prime_new_objects`], each
+    /// run may use the whole budget for its new objects, regardless of what earlier runs locked.
+    /// Process-wide [`LockMode`]s still lock everything, so with them the budget limits only
+    /// remapping.
+    #[inline]
+    #[must_use = "Options::budget returns the updated Options"]
+    pub fn budget(self, budget: Budget) -> Self {
+        Self {
+            budget: Some(budget),
+            ..self
+        }
+    }
+
+    /// Sets file name prefixes of shared objects to rank first within each tier of
+    /// [`Options::budget`], e.g. `["libc.so", "libstdc++.so"]`.
+    ///
+    /// Objects matching an earlier prefix rank higher. The rest follow in load order.
+    #[must_use = "Options::prefer_objects returns the updated Options"]
+    pub fn prefer_objects(self, prefixes: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            prefer_objects: prefixes.into_iter().map(Into::into).collect(),
+            ..self
+        }
+    }
+
+    /// Adds a filter deciding which segments to lock and remap; see [`Filter`].
+    ///
+    /// With several filters, a segment is locked (or remapped) only if every filter allows it.
+    /// Segments excluded from both are still listed in the [`Report`], with
+    /// [`SegmentReport::filter`] recording the decision.
+    ///
+    /// ```
+    /// use page_primer::{Action, Filter};
+    /// let options = page_primer::prime()
+    ///     .mlock(true)
+    ///     .remap(true)
+    ///     .filter(Filter::skip_writable())
+    ///     .filter(Filter::new(|o, _| match o.is_main {
+    ///         true => Action::BOTH,
+    ///         false => Action::MLOCK_ONLY,
+    ///     }));
+    /// ```
+    #[must_use = "Options::filter returns the updated Options"]
+    pub fn filter(self, filter: Filter) -> Self {
+        let mut filters = self.filters;
+        filters.push(filter);
+        Self { filters, ..self }
+    }
+
+    /// Sets whether to stop other threads while remapping, rather than skipping remapping if
+    /// any are running.
+    ///
+    /// Each other thread is sent a real-time signal (`SIGRTMAX - 1`) whose handler parks it with
+    /// all other signals blocked until remapping is done. Threads blocked in system calls are
+    /// fine, as remapping preserves memory contents. Loaded objects are listed before stopping,
+    /// as a thread may be stopped while holding the dynamic loader's lock. With glibc, if one was
+    /// stopped while loading or unloading an object, remapping is skipped; other C libraries
+    /// offer no way to check.
+    ///
+    /// If the signal already has a handler, remapping is skipped. If some thread doesn't stop
+    /// within 100 ms, e.g. because it blocks the signal, every stopped thread is resumed and
+    /// remapping is skipped; the signal is discarded, so the thread never runs the handler. In
+    /// each case the reason is recorded in [`Report::remap_skipped`]. The pause is recorded in
+    /// [`Report::world_stopped`]. Locking happens after other threads resume.
+    #[inline]
+    #[must_use = "Options::stop_the_world returns the updated Options"]
+    pub fn stop_the_world(self, stop_the_world: bool) -> Self {
+        Self {
+            stop_the_world,
+            ..self
+        }
+    }
+
+    /// Sets whether to verify the outcome via `/proc/self/smaps` after priming.
+    ///
+    /// This fills in [`SegmentReport::coverage`] and [`Report::vm_lck`], confirming whether the
+    /// kernel actually backed remapped ranges with huge pages.
+    #[inline]
+    #[must_use = "Options::verify returns the updated Options"]
+    pub fn verify(self, verify: bool) -> Self {
+        Self { verify, ..self }
+    }
+
+    /// Sets the policy used by [`Options::try_run`].
+    #[inline]
+    #[must_use = "Options::strict returns the updated Options"]
+    pub fn strict(self, strict: Strict) -> Self {
+        Self { strict, ..self }
+    }
+
+    /// Computes what [`Options::run`] would do without modifying the address space.
+    ///
+    /// This describes the exact ranges which would be reserved, copied, remapped, and locked,
+    /// and the memory they'd cost, e.g. to estimate RAM overhead in canaries before enabling
+    /// remapping. It doesn't require a single thread. See [`Plan`] for its assumptions.
+    pub fn plan(&self) -> Plan {
+        #[cfg(target_os = "linux")]
+        return linux::plan(self);
+
+        #[cfg(not(target_os = "linux"))]
+        Plan::default()
+    }
+
+    /// Runs the selected operations, failing if the outcome violates the [`Strict`] policy.
+    ///
+    /// This is useful in CI and canary deployments to catch configuration problems which would
+    /// otherwise silently lose the benefit of priming.
+    pub fn try_run(self) -> Result<Output, PrimeError> {
+        let (strict, remap, mlock) = (self.strict.clone(), self.remap, self.mlock);
+        let output = self.run();
+        let violations = strict.check(remap, mlock, output.report());
+        if violations.is_empty() {
+            return Ok(output);
+        }
+        Err(PrimeError::new(output, violations))
+    }
+
+    /// Runs the selected operations on the given loaded objects, rather than all those visible to
+    /// `dl_iterate_phdr`.
+    ///
+    /// This is for dynamic loader hooks such as an `LD_AUDIT` library's `la_activity`, which see
+    /// objects before they're visible to `dl_iterate_phdr` (or from a different link-map
+    /// namespace). Unlike [`Options::run`], it doesn't check the thread count, write a manifest,
+    /// or log the memory map. The main executable is the object whose `dlpi_name` is empty and
+    /// whose `dlpi_addr` matches the load bias given by the auxiliary vector's `AT_PHDR`; other
+    /// objects, such as the vDSO, may also have an empty name.
+    ///
+    /// # Safety
+    ///
+    /// `infos` must describe objects which are fully mapped. If remapping, no other thread may
+    /// use the objects' segments while this runs, e.g. because the dynamic loader has mapped them
+    /// but not yet relocated them or returned them from `dlopen`.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn run_objects(self, infos: &[libc::dl_phdr_info]) -> Output {
+        unsafe { linux::run_objects(self, infos) }
+    }
+
     /// Runs the selected operations.
-    #[must_use]
+    #[must_use = "Options::run returns the Output, which should be logged"]
     pub fn run(self) -> Output {
+        #[cfg(feature = "serde")]
+        let manifest = self.manifest.clone();
+
+        #[allow(unused_mut)]
+        let mut out = self.run_platform();
+
+        #[cfg(feature = "serde")]
+        out.write_manifest(manifest);
+        out
+    }
+
+    /// Runs the selected operations, locking on a background thread.
+    ///
+    /// Remapping requires a single thread, so it still happens before this returns. Locking
+    /// faults in every page, which can take seconds for a large binary on slow storage, so it
+    /// happens afterward; the returned [`Background`] reports its progress and final output.
+    /// Segments are unlocked until then, so [`SegmentReport::mlock`] is `None` in the meantime.
+    pub fn run_background(self) -> Background {
+        #[cfg(feature = "serde")]
+        let manifest = self.manifest.clone();
+        let (mlock, lock_mode, verify) = (self.mlock, self.lock_mode, self.verify);
+
+        #[allow(unused_mut)]
+        let mut out = Options {
+            background_lock: true,
+            ..self
+        }
+        .run_platform();
+        if !mlock || out.report.skipped.is_some() {
+            #[cfg(feature = "serde")]
+            out.write_manifest(manifest);
+            return Background::finished(out);
+        }
+
+        #[cfg(target_os = "linux")]
+        return Background::spawn(out, move |out, done| {
+            linux::lock_segments(&linux::Real, lock_mode, &mut out.report, done);
+            linux::summarize(verify, &mut out.report, &mut out.log);
+
+            #[cfg(feature = "serde")]
+            out.write_manifest(manifest);
+        });
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (lock_mode, verify);
+            Background::finished(out)
+        }
+    }
+
+    fn run_platform(self) -> Output {
         #[cfg(target_os = "linux")]
         return linux::run(self);
 
         #[cfg(not(target_os = "linux"))]
-        return Output { log: Vec::new() };
+        Output {
+            log: Vec::new(),
+            report: Report::default(),
+        }
+    }
+}
+
+/// How segments are remapped into huge pages.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum RemapStrategy {
+    /// Copies each segment into a `memfd_create(MFD_HUGETLB)` file mapped over the original.
+    ///
+    /// This requires huge pages reserved in the hugetlb pool, e.g. via
+    /// `/proc/sys/vm/nr_hugepages`.
+    #[default]
+    HugetlbMemfd,
+
+    /// Copies each segment into anonymous memory advised with `MADV_HUGEPAGE`, then moves it over
+    /// the original with `mremap`.
+    ///
+    /// This requires transparent huge pages enabled in `always` or `madvise` mode
+    /// (`/sys/kernel/mm/transparent_hugepage/enabled`), but no reserved pool. Mappings are named
+    /// after the object where the kernel supports `PR_SET_VMA_ANON_NAME`.
+    AnonThp,
+
+    /// Copies each segment into a `memfd_create` file without `MFD_HUGETLB`, advised with
+    /// `MADV_HUGEPAGE`, mapped over the original.
+    ///
+    /// This requires shmem transparent huge pages enabled in `always`, `within_size`, or `advise`
+    /// mode (`/sys/kernel/mm/transparent_hugepage/shmem_enabled`), but no reserved pool. Like
+    /// [`RemapStrategy::HugetlbMemfd`], mappings appear as `/memfd:<path>` in `/proc/<pid>/maps`.
+    ShmemMemfd,
+
+    /// Collapses the huge pages wholly within each segment in place via `madvise(MADV_COLLAPSE)`.
+    ///
+    /// This requires Linux 6.1 or later, built with `CONFIG_READ_ONLY_THP_FOR_FS`. Unlike the
+    /// other strategies, it neither copies nor replaces mappings, so text remains file-backed and
+    /// debuggers, profilers, and uprobes keep working. Segment heads and tails outside whole huge
+    /// pages remain in base pages.
+    Collapse,
+}
+
+impl RemapStrategy {
+    /// All strategies, in the order used by [`Options::remap_auto`]: those that guarantee huge
+    /// pages or preserve file-backed text first, then those relying on transparent huge pages.
+    pub const AUTO: &'static [RemapStrategy] = &[
+        RemapStrategy::HugetlbMemfd,
+        RemapStrategy::Collapse,
+        RemapStrategy::ShmemMemfd,
+        RemapStrategy::AnonThp,
+    ];
+}
+
+impl std::fmt::Display for RemapStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RemapStrategy::HugetlbMemfd => "hugetlb memfd",
+            RemapStrategy::AnonThp => "anonymous THP",
+            RemapStrategy::ShmemMemfd => "shmem THP memfd",
+            RemapStrategy::Collapse => "in-place collapse",
+        })
+    }
+}
+
+/// How segments are locked into memory by [`Options::mlock`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum LockMode {
+    /// Locks each segment with `mlock`, faulting in every page immediately, including cold code
+    /// which may never run.
+    #[default]
+    Populate,
+
+    /// Locks each segment with `mlock2(MLOCK_ONFAULT)`, so pages are faulted in as usual but never
+    /// evicted once touched.
+    ///
+    /// This requires Linux 4.4 or later.
+    OnFault,
+
+    /// Locks the whole process with `mlockall(MCL_CURRENT | MCL_FUTURE)`, including the heap,
+    /// stacks, and anything mapped later.
+    ///
+    /// Later mappings fail rather than exceed `RLIMIT_MEMLOCK`, so this suits processes which
+    /// want everything pinned and have an unlimited limit or `CAP_IPC_LOCK`.
+    All,
+
+    /// As with [`LockMode::All`], plus `MCL_ONFAULT` so pages are locked only once touched.
+    ///
+    /// This requires Linux 4.4 or later.
+    AllOnFault,
+}
+
+impl LockMode {
+    /// Returns true iff this locks the whole process rather than each segment.
+    #[inline]
+    pub fn is_process_wide(self) -> bool {
+        matches!(self, LockMode::All | LockMode::AllOnFault)
     }
 }
 
+impl std::fmt::Display for LockMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LockMode::Populate => "mlock",
+            LockMode::OnFault => "mlock2 on fault",
+            LockMode::All => "mlockall",
+            LockMode::AllOnFault => "mlockall on fault",
+        })
+    }
+}
+
+/// The result of [`Options::run`]: log messages for humans and a [`Report`] for programs.
 #[must_use = "Output does nothing unless Output::log or Output::eprint is called"]
 pub struct Output {
     log: Vec<(log::Level, String)>,
+    report: Report,
 }
 
 impl Output {
+    /// Returns a structured description of what was done.
+    #[inline]
+    pub fn report(&self) -> &Report {
+        &self.report
+    }
+
+    /// Returns a structured description of what was done, discarding the log messages.
+    #[inline]
+    pub fn into_report(self) -> Report {
+        self.report
+    }
+
     /// Logs output using the [`log`] crate.
     pub fn log(&self) {
         for (level, msg) in &self.log {
@@ -60,6 +508,81 @@ impl Output {
             eprintln!("{msg}");
         }
     }
+
+    #[cfg(feature = "serde")]
+    fn write_manifest(&mut self, path: Option<std::path::PathBuf>) {
+        let Some(path) = path else {
+            return;
+        };
+        self.log.push(match self.report.write_json(&path) {
+            Ok(()) => (
+                log::Level::Debug,
+                format!("Wrote page priming manifest to {}.", path.display()),
+            ),
+            Err(e) => (
+                log::Level::Warn,
+                format!(
+                    "Unable to write page priming manifest to {}: {e}",
+                    path.display()
+                ),
+            ),
+        });
+    }
+
+    /// Saves output for a later [`take_output`], replacing any not yet taken.
+    ///
+    /// This is used by [`auto!`] and, unless given another `hook`, by `#[page_primer::main]`.
+    pub fn stash(self) {
+        *STASHED.lock().unwrap_or_else(PoisonError::into_inner) = Some(self);
+    }
+}
+
+/// Output saved by [`Output::stash`].
+static STASHED: Mutex<Option<Output>> = Mutex::new(None);
+
+/// Returns the output saved by [`Output::stash`], e.g. from [`auto!`], if any.
+pub fn take_output() -> Option<Output> {
+    STASHED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+}
+
+/// Registers an ELF constructor which runs the given [`Options`] before `main`, saving the
+/// [`Output`] for [`take_output`].
+///
+/// Constructors run before any threads are started by `main`, by the Rust test harness, or by
+/// async runtimes, so this also primes `cargo test` binaries. Invoke it once, in the binary (or,
+/// for tests, the crate under test); a constructor in a dependency may not be linked. If priming
+/// panics, the panic is caught and [`take_output`] returns `None`.
+///
+/// ```rust
+/// page_primer::auto!(page_primer::prime().mlock(true));
+///
+/// fn main() {
+///     // ...set up logging...
+///     if let Some(out) = page_primer::take_output() {
+///         out.log();
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! auto {
+    ($options:expr) => {
+        const _: () = {
+            extern "C" fn page_primer_auto() {
+                // Unwinding out of a constructor is undefined behavior, and it's too early for
+                // a panic hook or logger to report anything, so just leave nothing stashed.
+                let _ = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+                    $crate::Output::stash($crate::Options::run($options))
+                }));
+            }
+
+            #[used]
+            #[cfg_attr(target_os = "linux", link_section = ".init_array")]
+            static PAGE_PRIMER_AUTO: extern "C" fn() = page_primer_auto;
+        };
+    };
 }
 
 /// Returns a builder for priming operations.
@@ -67,3 +590,106 @@ impl Output {
 pub fn prime() -> Options {
     Options::default()
 }
+
+/// Returns a builder for priming only objects loaded since the last run, e.g. libraries
+/// `dlopen`ed after startup.
+///
+/// The first run in a process primes all objects. Later runs skip those already seen, using
+/// `dl_phdr_info::dlpi_adds` to avoid walking the program headers when nothing has been loaded,
+/// and report [`Skipped::NoNewObjects`] if there's nothing new. An object `dlclose`d and reloaded
+/// since the last run is primed again, as `dl_phdr_info::dlpi_subs` reveals the unload.
+///
+/// As with [`prime`], remapping requires that only one thread is running; that's rarely the case
+/// after startup, so typically only [`Options::mlock`] is effective. Locking proceeds regardless of
+/// the thread count, with any skipped remapping noted in [`Report::remap_skipped`].
+#[inline]
+pub fn prime_new_objects() -> Options {
+    Options {
+        new_objects_only: true,
+        ..Options::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Options which need no privileges, so the constructor always runs to completion.
+    crate::auto!(crate::prime().verify(true));
+
+    /// Checks the constructor ran before the test harness started its threads.
+    #[test]
+    fn auto() {
+        let out = crate::take_output().expect("constructor stashed output");
+        assert_eq!(out.report().skipped, Some(crate::Skipped::NothingToDo));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn plan_budget() {
+        let plan = crate::prime()
+            .mlock(true)
+            .budget(crate::Budget::Bytes(0))
+            .plan();
+        assert_eq!(plan.budget, Some(0));
+        assert!(!plan.segments.is_empty());
+        assert!(plan
+            .segments
+            .iter()
+            .all(|s| s.deprioritized && s.lock.is_none()));
+    }
+
+    /// Checks segments there's nothing to do with aren't reported as deprioritized by the budget.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn plan_budget_remap_only() {
+        let plan = crate::prime()
+            .remap(true)
+            .remap_strategy(crate::RemapStrategy::AnonThp)
+            .budget(crate::Budget::Bytes(0))
+            .plan();
+        let writable = |s: &crate::SegmentPlan| (s.flags & crate::report::PF_W) != 0;
+        assert!(plan.segments.iter().any(writable));
+        for seg in &plan.segments {
+            let remappable = plan.huge_page_size.is_some() && !writable(seg);
+            assert_eq!(seg.deprioritized, remappable, "{seg:?}");
+        }
+    }
+
+    /// Checks segments a filter excludes aren't reported as deprioritized by the budget.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn filter_with_budget() {
+        let _guard = crate::linux::LIVE_TEST_LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let options = || {
+            crate::prime()
+                .mlock(true)
+                .budget(crate::Budget::Bytes(0))
+                .filter(crate::Filter::main_only())
+        };
+        let out = options().run();
+        assert!(out.report().deprioritized_segments().count() > 0);
+        for seg in &out.report().segments {
+            assert_eq!(seg.deprioritized, seg.object_i == 0, "{seg:?}");
+            assert_eq!(seg.mlock, None);
+        }
+        for seg in &options().plan().segments {
+            assert_eq!(seg.deprioritized, seg.object_i == 0, "{seg:?}");
+            assert_eq!(seg.lock, None);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn run_background() {
+        let _guard = crate::linux::LIVE_TEST_LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let background = crate::prime().mlock(true).run_background();
+        let total = background.progress().total;
+        assert!(total > 0);
+        let out = background.join();
+        assert!(!out.report().segments.is_empty());
+        assert!(out.report().segments.iter().all(|s| s.mlock.is_some()));
+    }
+}
@@ -1,17 +1,33 @@
 // Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-use crate::Output;
-use libc::memfd_create;
-use std::ffi::{CStr, OsString};
-use std::fmt::Write as _;
+use crate::plan::{plan_replacement, round_up, Planner, Replacement, SegmentPlan};
+use crate::report::{HugeError, ObjectReport, Report, SegmentReport, Skipped};
+use crate::SegmentInfo;
+use crate::{budget, Action, Budget, Filter, LockMode, ObjectInfo, Output, Plan, RemapStrategy};
+use std::ffi::{CStr, OsStr, OsString};
 use std::io::{Error, ErrorKind};
 use std::ops::Range;
 use std::os::unix::ffi::OsStrExt as _;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+mod memlock;
+mod probe;
+#[cfg(test)]
+mod sim;
+mod smaps;
+mod stw;
+mod sys;
+
+pub(crate) use sys::Real;
+use sys::Sys;
 
 const HPAGE_PMD_SIZE_PATH: &str = "/sys/kernel/mm/transparent_hugepage/hpage_pmd_size";
+const CGROUP_PATH: &str = "/proc/self/cgroup";
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
 
 /// Turns a page size (which must be a power of 2) into a mask.
 fn mask(page_size: usize) -> usize {
@@ -36,6 +52,222 @@ pub(crate) fn huge_page_size() -> Result<Option<usize>, Error> {
     Some(parse_huge_page_size(&v)).transpose()
 }
 
+/// Returns the smallest cgroup v2 `memory.max` of this process's cgroup and its ancestors, or
+/// `None` if unlimited.
+fn cgroup_memory_max() -> Result<Option<usize>, Error> {
+    let cgroup = std::fs::read_to_string(CGROUP_PATH)?;
+    let path = cgroup
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("{CGROUP_PATH} has no cgroup v2 entry"),
+            )
+        })?;
+    let root = std::path::Path::new(CGROUP_ROOT);
+    let mut dir = root.join(path.trim_start_matches('/'));
+    let mut limit: Option<usize> = None;
+    while dir.starts_with(root) && dir != root {
+        let file = dir.join("memory.max");
+        match std::fs::read_to_string(&file) {
+            Ok(data) => {
+                if let Some(l) = parse_memory_max(&data).map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unable to parse {}: {e}", file.display()),
+                    )
+                })? {
+                    limit = Some(limit.map_or(l, |prev| std::cmp::min(prev, l)));
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        dir.pop();
+    }
+    Ok(limit)
+}
+
+/// Parses a cgroup v2 `memory.max` value: bytes, or `max` if unlimited.
+fn parse_memory_max(data: &str) -> Result<Option<usize>, std::num::ParseIntError> {
+    match data.trim() {
+        "max" => Ok(None),
+        v => usize::from_str(v).map(Some),
+    }
+}
+
+/// Resolves `budget` to bytes, or `None` if it imposes no limit.
+fn resolve_budget(budget: Budget, log: &mut Vec<(log::Level, String)>) -> Option<usize> {
+    let percent = match budget {
+        Budget::Bytes(b) => return Some(b),
+        Budget::CgroupPercent(p) => p.min(100),
+    };
+    match cgroup_memory_max() {
+        Ok(Some(max)) => Some((max as u128 * u128::from(percent) / 100) as usize),
+        Ok(None) => {
+            log.push((
+                log::Level::Info,
+                "No cgroup memory limit, so no priming budget.".to_owned(),
+            ));
+            None
+        }
+        Err(e) => {
+            log.push((
+                log::Level::Warn,
+                format!("Unable to read cgroup memory limit, so no priming budget: {e}"),
+            ));
+            None
+        }
+    }
+}
+
+/// Evaluates `filters` for each segment of `walk`, returning the combined actions sorted by start
+/// address. `main` is the index of the main executable within `walk.objects`, if present.
+fn filter_actions(
+    filters: &[Filter],
+    walk: &PlanContext,
+    main: Option<usize>,
+) -> Vec<(usize, Action)> {
+    let mut actions: Vec<_> = walk
+        .segments
+        .iter()
+        .map(|&(object_i, flags, ref addrs)| {
+            let object = ObjectInfo {
+                path: &walk.objects[object_i].path,
+                is_main: main == Some(object_i),
+            };
+            let segment = SegmentInfo {
+                flags,
+                addrs: addrs.clone(),
+            };
+            let action = filters
+                .iter()
+                .fold(Action::BOTH, |a, f| a.and(f.apply(&object, &segment)));
+            (addrs.start, action)
+        })
+        .collect();
+    actions.sort_unstable_by_key(|&(start, _)| start);
+    actions
+}
+
+/// Returns the action for the segment starting at `start`, or [`Action::NEITHER`] for segments
+/// which weren't loaded when the filters were evaluated.
+fn action_at(actions: &[(usize, Action)], start: usize) -> Action {
+    match actions.binary_search_by_key(&start, |&(s, _)| s) {
+        Ok(i) => actions[i].1,
+        Err(_) => Action::NEITHER,
+    }
+}
+
+/// Estimates the bytes locking the segments of `walk` (or of `only`) would pin, for
+/// [`memlock::preflight`]. `action` gives each segment's decision, as from [`segment_action`].
+///
+/// Remapped segments are locked in whole huge pages, so this plans remapping as
+/// [`crate::Options::plan`] does. Remapping falls back through the strategies, so each segment
+/// counts its largest planned lock among them.
+fn lock_estimate(
+    config: &RemapConfig,
+    walk: &PlanContext,
+    only: Option<&[ObjectKey]>,
+    action: impl Fn(&Range<usize>) -> Action,
+) -> usize {
+    let segments: Vec<_> = walk
+        .segments
+        .iter()
+        .filter(|(object_i, _, _)| only.map_or(true, |o| o.contains(&walk.keys[*object_i])))
+        .cloned()
+        .collect();
+    let huge_page_sizes = config.huge_page_sizes();
+    let planner = config.planner(&huge_page_sizes, true);
+    let occupied = smaps::read_maps().ok();
+    let mut needed = vec![0; segments.len()];
+    let strategies: Vec<_> = match planner.strategy {
+        Some(_) => config.remap_strategies.iter().copied().map(Some).collect(),
+        None => vec![None],
+    };
+    for strategy in strategies {
+        let planner = Planner {
+            strategy,
+            ..planner
+        };
+        let plans = plan_segments(
+            &planner,
+            segments.iter().cloned(),
+            &action,
+            occupied.clone(),
+        );
+        for (n, plan) in needed.iter_mut().zip(plans) {
+            *n = std::cmp::max(*n, plan.lock.map_or(0, |l| l.len()));
+        }
+    }
+    needed.iter().sum()
+}
+
+/// Returns what to do with the segment starting at `start`, given the filters' `actions` and the
+/// segments `allowed` by the budget, if any.
+fn segment_action(
+    allowed: Option<&[usize]>,
+    actions: Option<&[(usize, Action)]>,
+    start: usize,
+) -> Action {
+    let action = actions.map(|a| action_at(a, start));
+    match is_deprioritized(allowed, action, start) {
+        true => Action::NEITHER,
+        false => action.unwrap_or(Action::BOTH),
+    }
+}
+
+/// Returns true iff the segment starting at `start` was a candidate for the budget but wasn't
+/// chosen. Segments the filters exclude entirely aren't candidates.
+fn is_deprioritized(allowed: Option<&[usize]>, action: Option<Action>, start: usize) -> bool {
+    action != Some(Action::NEITHER) && allowed.is_some_and(|a| a.binary_search(&start).is_err())
+}
+
+/// Chooses the segments to prime within `budget` bytes, as described in
+/// [`crate::Options::budget`], returning their sorted start addresses.
+fn select_within_budget(
+    options: &super::Options,
+    config: &RemapConfig,
+    walk: &PlanContext,
+    only: Option<&[ObjectKey]>,
+    actions: Option<&[(usize, Action)]>,
+    budget: usize,
+) -> Vec<usize> {
+    let base_mask = mask(base_page_size());
+    let action = |addrs: &Range<usize>| actions.map_or(Action::BOTH, |a| action_at(a, addrs.start));
+    let remaps = |flags: ElfWord, addrs: &Range<usize>| {
+        config.huge_page_size.is_some() && (flags & PF_W) == 0 && action(addrs).remap
+    };
+    let cost = |flags: ElfWord, addrs: &Range<usize>| {
+        let mask = match config.huge_page_size {
+            Some(h) if remaps(flags, addrs) => mask(h),
+            _ => base_mask,
+        };
+        round_up(addrs.end, mask) - (addrs.start & !mask)
+    };
+
+    // Segments with nothing to do cost nothing, so they're allowed without being ranked.
+    let (segments, idle): (Vec<_>, Vec<_>) = walk
+        .segments
+        .iter()
+        .filter(|(object_i, _, _)| only.map_or(true, |o| o.contains(&walk.keys[*object_i])))
+        .cloned()
+        .partition(|(_, flags, addrs)| {
+            remaps(*flags, addrs) || (options.mlock && action(addrs).mlock)
+        });
+    let mut allowed = budget::select(
+        &walk.objects,
+        &segments,
+        &options.prefer_objects,
+        budget,
+        cost,
+    );
+    allowed.extend(idle.iter().map(|(_, _, addrs)| addrs.start));
+    allowed.sort_unstable();
+    allowed
+}
+
 fn parse_huge_page_size(data: &[u8]) -> Result<usize, Error> {
     let data = std::str::from_utf8(data).map_err(|e| {
         Error::new(
@@ -68,68 +300,247 @@ fn program_name() -> OsString {
 }
 
 #[cfg(target_pointer_width = "64")]
-use libc::Elf64_Word as ElfWord;
+use libc::{Elf64_Phdr as ElfPhdr, Elf64_Word as ElfWord};
 
 #[cfg(target_pointer_width = "32")]
-use libc::Elf32_Word as ElfWord;
+use libc::{Elf32_Phdr as ElfPhdr, Elf32_Word as ElfWord};
+
+/// Returns the load bias of the main executable, computed from the auxiliary vector as the
+/// dynamic loader does.
+fn main_bias() -> usize {
+    let phdr = unsafe { libc::getauxval(libc::AT_PHDR) } as usize;
+    let phnum = unsafe { libc::getauxval(libc::AT_PHNUM) } as usize;
+    if phdr == 0 {
+        return 0;
+    }
+    let phdrs = unsafe { std::slice::from_raw_parts(phdr as *const ElfPhdr, phnum) };
+    phdrs
+        .iter()
+        .find(|p| p.p_type == libc::PT_PHDR)
+        .map_or(0, |p| phdr.wrapping_sub(p.p_vaddr as usize))
+}
 
 // ELF protection flags, cast appropriately.
 const PF_R: ElfWord = libc::PF_R as ElfWord;
 const PF_W: ElfWord = libc::PF_W as ElfWord;
 const PF_X: ElfWord = libc::PF_X as ElfWord;
 
-/// Returns a debug string describing the given ELF protection flags.
-fn debug_prot(p_flags: ElfWord) -> String {
-    let r = if (p_flags & PF_R) != 0 { "r" } else { "-" };
-    let w = if (p_flags & PF_R) != 0 { "w" } else { "-" };
-    let x = if (p_flags & PF_R) != 0 { "x" } else { "-" };
-    format!("{r}{w}{x}")
-}
+/// `MADV_COLLAPSE`, which not all `libc` targets define.
+const MADV_COLLAPSE: libc::c_int = 25;
 
 /// Context pointer for `phdr_cb`.
-struct Context {
-    mlock: bool,
+///
+/// `phdr_cb` must not allocate: a new heap mapping could land within a huge page it's trying to
+/// reserve. Thus `objects` and `segments` are preallocated from a [`Snapshot`].
+struct Context<'a> {
+    /// The system calls to use: [`Real`] except in tests.
+    sys: &'a dyn Sys,
+
+    /// The mode for locking each segment as it's visited, iff it should be.
+    mlock: Option<LockMode>,
+
+    /// The mode for locking all segments after the walk, iff they should be locked then instead.
+    deferred_mlock: Option<LockMode>,
     base_page_mask: usize,
 
-    /// A mask for huge pages, iff huge page remapping should be performed.
+    /// Masks for the usable huge page sizes, largest first; empty iff huge page remapping
+    /// should not be performed.
+    ///
+    /// The first is the PMD-sized huge page, used by every strategy. Any others are smaller
+    /// multi-size THP (mTHP) sizes, which [`RemapStrategy::AnonThp`] falls back to on conflict.
     #[cfg(target_os = "linux")]
-    huge_page_mask: Option<usize>,
+    huge_page_masks: Vec<usize>,
+    remap_strategies: Vec<RemapStrategy>,
+
+    /// A mask for large hugetlb pages (e.g. 1 GiB), iff they should be used.
+    large_page_mask: Option<usize>,
+
+    /// The objects to visit, or `None` to visit all.
+    only: Option<Vec<ObjectKey>>,
+
+    /// The sorted start addresses of segments within [`crate::Options::budget`], or `None` if
+    /// there's no budget.
+    allowed: Option<Vec<usize>>,
+
+    /// The filters' decision for each segment by start address, or `None` if there are no
+    /// filters.
+    actions: Option<Vec<(usize, Action)>>,
+
+    /// The number of objects `dl_iterate_phdr` has passed, visited or not.
+    walked: usize,
 
     next_object_i: usize,
     program_name: OsString,
-    segments: Vec<Segment>,
+    objects: Vec<Object>,
+    segments: Vec<SegmentReport>,
+    segments_dropped: usize,
+}
+
+/// An ELF object, as recorded by `phdr_cb`.
+struct Object {
+    /// A NUL-terminated string describing the path to the object.
+    path: [u8; libc::PATH_MAX as usize],
+    load_bias: usize,
+    is_main: bool,
 }
 
-/// An ELF loadable program segment.
-struct Segment {
-    /// The object index to which this segment belongs; two `Segment`s come from
-    /// the same ELF shared object if they have the same `object_i`.
-    object_i: usize,
+/// An ELF loadable program segment, as needed for remapping.
+struct Segment<'a> {
+    sys: &'a dyn Sys,
     flags: ElfWord,
 
     /// The virtual address range.
     addrs: Range<usize>,
 
-    /// The result of remapping into a huge page.
-    #[cfg(target_os = "linux")]
-    remap: Option<Result<Range<usize>, HugeError>>,
+    /// A NUL-terminated string describing the path to the object.
+    path: *const libc::c_char,
+}
 
-    /// The result of `mlock`.
-    mlock: Option<Result<(), libc::c_int>>,
+/// Identifies a loaded object by its load bias and program header address.
+type ObjectKey = (usize, usize);
 
-    /// A NUL-terminated string describing the path to the object.
-    path: [u8; libc::PATH_MAX as usize],
+fn object_key(info: &libc::dl_phdr_info) -> ObjectKey {
+    (info.dlpi_addr as usize, info.dlpi_phdr as usize)
+}
+
+/// Returns the number of `PT_LOAD` segments in `info` and their total size in whole base pages.
+///
+/// SAFETY: `info` must describe a loaded object.
+unsafe fn load_sizes(info: &libc::dl_phdr_info) -> (usize, usize) {
+    let mask = mask(base_page_size());
+    let segs = unsafe { std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize) };
+    segs.iter()
+        .filter(|s| s.p_type == libc::PT_LOAD)
+        .fold((0, 0), |(loads, bytes), s| {
+            let vaddr = info.dlpi_addr.wrapping_add(s.p_vaddr) as usize;
+            let pages = (vaddr & !mask)..((vaddr + s.p_memsz as usize + mask) & !mask);
+            (loads + 1, bytes + pages.len())
+        })
 }
 
-fn errno() -> i32 {
-    unsafe { (*libc::__errno_location()) as i32 }
+/// The loaded objects at a point in time, as recorded by `snapshot_cb`.
+#[derive(Default)]
+struct Snapshot {
+    /// The number of objects ever loaded (`dlpi_adds`), if supported by the C library.
+    adds: Option<u64>,
+
+    /// The number of objects ever unloaded (`dlpi_subs`), if supported by the C library.
+    subs: Option<u64>,
+
+    /// Each object, its number of `PT_LOAD` segments, and their total size in whole base pages,
+    /// in `dl_iterate_phdr` order.
+    objects: Vec<(ObjectKey, usize, usize)>,
 }
 
-unsafe fn mlock(range: Range<usize>) -> Result<(), libc::c_int> {
-    if unsafe { libc::mlock(range.start as *const libc::c_void, range.len()) } == -1 {
-        return Err(errno());
+/// The objects seen by the last run, for [`crate::prime_new_objects`].
+static SEEN: Mutex<Option<Snapshot>> = Mutex::new(None);
+
+/// Serializes tests which prime the real process, as they share [`SEEN`] and the memory map.
+#[cfg(test)]
+pub(crate) static LIVE_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+impl Snapshot {
+    fn take() -> Self {
+        let mut snapshot = Snapshot::default();
+        unsafe {
+            libc::dl_iterate_phdr(
+                Some(snapshot_cb),
+                &mut snapshot as *mut Snapshot as *mut libc::c_void,
+            )
+        };
+        snapshot
     }
-    Ok(())
+
+    /// Returns true iff no object could have been loaded between `prev` and `self`.
+    fn unchanged_since(&self, prev: &Snapshot) -> bool {
+        match (self.adds, prev.adds) {
+            (Some(a), Some(p)) => a == p,
+            _ => false,
+        }
+    }
+
+    /// Returns the keys of objects which may have been loaded between `prev` and `self`.
+    ///
+    /// An object `dlclose`d and reloaded at the same address keeps its key. So if any object was
+    /// unloaded in between, the last `dlpi_adds - prev.dlpi_adds` objects are included too; the
+    /// dynamic loader appends newly loaded objects to the end of its list.
+    fn new_since(&self, prev: &Snapshot) -> Vec<ObjectKey> {
+        let added = match (self.adds, prev.adds, self.subs, prev.subs) {
+            (Some(a), Some(pa), Some(s), Some(ps)) if s != ps => a.wrapping_sub(pa) as usize,
+            _ => 0,
+        };
+        let tail = self.objects.len().saturating_sub(added);
+        self.objects
+            .iter()
+            .enumerate()
+            .filter(|(i, (key, _, _))| *i >= tail || !prev.objects.iter().any(|(k, _, _)| k == key))
+            .map(|(_, &(key, _, _))| key)
+            .collect()
+    }
+}
+
+/// Returns a copy of each loaded object's `dl_phdr_info`, in `dl_iterate_phdr` order.
+fn loaded_infos() -> Vec<libc::dl_phdr_info> {
+    let mut infos: Vec<libc::dl_phdr_info> = Vec::new();
+    unsafe {
+        libc::dl_iterate_phdr(
+            Some(infos_cb),
+            &mut infos as *mut Vec<libc::dl_phdr_info> as *mut libc::c_void,
+        )
+    };
+    infos
+}
+
+/// Callback supplied to `dl_iterate_phdr` by [`loaded_infos`].
+unsafe extern "C" fn infos_cb(
+    info: *mut libc::dl_phdr_info,
+    size: libc::size_t,
+    data: *mut libc::c_void,
+) -> libc::c_int {
+    let infos = unsafe { &mut *(data as *mut Vec<libc::dl_phdr_info>) };
+
+    // Copy only the fields this C library supports, leaving any others zeroed.
+    let mut copy: libc::dl_phdr_info = unsafe { std::mem::zeroed() };
+    let len = std::cmp::min(size, std::mem::size_of::<libc::dl_phdr_info>());
+    unsafe {
+        std::ptr::copy_nonoverlapping(info as *const u8, &mut copy as *mut _ as *mut u8, len)
+    };
+    if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| infos.push(copy))).is_err() {
+        eprintln!("Aborting due to infos_cb failure.");
+        std::process::abort();
+    }
+    0
+}
+
+/// Callback supplied to `dl_iterate_phdr` to take a [`Snapshot`].
+///
+/// This runs before any memory is touched, so unlike `phdr_cb` it may allocate.
+unsafe extern "C" fn snapshot_cb(
+    info: *mut libc::dl_phdr_info,
+    size: libc::size_t,
+    data: *mut libc::c_void,
+) -> libc::c_int {
+    let snapshot = unsafe { &mut *(data as *mut Snapshot) };
+    let info = unsafe { &*info };
+    if size >= std::mem::size_of::<libc::dl_phdr_info>() {
+        snapshot.adds = Some(info.dlpi_adds);
+        snapshot.subs = Some(info.dlpi_subs);
+    }
+    let (loads, bytes) = unsafe { load_sizes(info) };
+    if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        snapshot.objects.push((object_key(info), loads, bytes))
+    }))
+    .is_err()
+    {
+        eprintln!("Aborting due to snapshot_cb failure.");
+        std::process::abort();
+    }
+    0
+}
+
+/// Performs the operations described by `ctx` on every loaded object.
+fn walk(ctx: &mut Context<'_>) {
+    unsafe { libc::dl_iterate_phdr(Some(phdr_cb), ctx as *mut Context as *mut libc::c_void) };
 }
 
 /// Callback supplied to `dl_iterate_phdr`.
@@ -151,12 +562,34 @@ unsafe extern "C" fn phdr_cb(
     0
 }
 
-unsafe fn phdr_cb_inner(info: &libc::dl_phdr_info, ctx: &mut Context) {
-    let name = if ctx.next_object_i == 0 {
+unsafe fn phdr_cb_inner(info: &libc::dl_phdr_info, ctx: &mut Context<'_>) {
+    let is_main = ctx.walked == 0;
+    ctx.walked += 1;
+    if let Some(only) = ctx.only.as_ref() {
+        if !only.contains(&object_key(info)) {
+            return;
+        }
+    }
+    let name = if is_main {
         ctx.program_name.as_bytes()
     } else {
         unsafe { CStr::from_ptr(info.dlpi_name) }.to_bytes()
     };
+    let mut path = [0; libc::PATH_MAX as usize];
+    let name_copy_len = std::cmp::min(name.len(), libc::PATH_MAX as usize - 1);
+    path[..name_copy_len].copy_from_slice(&name[..name_copy_len]);
+    let object_i = ctx.next_object_i;
+    ctx.next_object_i += 1;
+    let path = if ctx.objects.len() < ctx.objects.capacity() {
+        ctx.objects.push(Object {
+            path,
+            load_bias: info.dlpi_addr as usize,
+            is_main,
+        });
+        &ctx.objects[object_i].path
+    } else {
+        &path
+    };
     let segs = unsafe { std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize) };
     for seg in segs {
         if seg.p_type != libc::PT_LOAD {
@@ -164,38 +597,55 @@ unsafe fn phdr_cb_inner(info: &libc::dl_phdr_info, ctx: &mut Context) {
         }
         let vaddr = info.dlpi_addr.wrapping_add(seg.p_vaddr) as usize;
         let vend = vaddr + seg.p_memsz as usize;
-        let mut path = [0; libc::PATH_MAX as usize];
-        let name_copy_len = std::cmp::min(name.len(), libc::PATH_MAX as usize - 1);
-        path[..name_copy_len].copy_from_slice(&name[..name_copy_len]);
-        let mut seg = Segment {
-            object_i: ctx.next_object_i,
+        let seg = Segment {
+            sys: ctx.sys,
             flags: seg.p_flags,
             addrs: vaddr..vend,
+            path: path.as_ptr() as *const libc::c_char,
+        };
+        let action = ctx.actions.as_ref().map(|a| action_at(a, vaddr));
+        let mut report = SegmentReport {
+            object_i,
+            flags: seg.flags,
+            addrs: seg.addrs.clone(),
             remap: None,
+            remap_strategy: None,
+            remap_fallbacks: Default::default(),
+            large_pages: None,
+            mthp_size: None,
             mlock: None,
-            path,
+            mlock_mode: None,
+            deprioritized: is_deprioritized(ctx.allowed.as_deref(), action, vaddr),
+            filter: action,
+            coverage: None,
         };
 
         #[cfg(target_os = "linux")]
-        if let Some(huge_page_mask) = ctx.huge_page_mask {
-            seg.remap = Some(unsafe { seg.remap(ctx.base_page_mask, huge_page_mask) });
+        if !ctx.huge_page_masks.is_empty() && report.remap_wanted() {
+            unsafe {
+                seg.remap(
+                    ctx.base_page_mask,
+                    &ctx.huge_page_masks,
+                    ctx.large_page_mask,
+                    &ctx.remap_strategies,
+                    &mut report,
+                )
+            };
         }
-        if ctx.mlock {
-            seg.mlock = Some(unsafe { mlock(seg.addrs.clone()) });
+        if let (Some(mode), true) = (ctx.mlock, report.lock_wanted()) {
+            // Lock the whole remapped range, if any. Locking only part would split the VMA and
+            // with it any transparent huge pages.
+            let range = report.lock_range(ctx.base_page_mask + 1);
+            report.mlock = Some(unsafe { ctx.sys.mlock(range, mode == LockMode::OnFault) });
+            report.mlock_mode = Some(mode);
         }
 
         if ctx.segments.len() < ctx.segments.capacity() {
-            ctx.segments.push(seg);
+            ctx.segments.push(report);
+        } else {
+            ctx.segments_dropped += 1;
         }
     }
-    ctx.next_object_i += 1;
-}
-
-fn round_up(addr: usize, mask: usize) -> usize {
-    match (addr & mask) != 0 {
-        true => (addr & !mask) + mask + 1,
-        false => addr,
-    }
 }
 
 /// Transforms ELF `PF_*` protection flags into `PROT_*` as suitable in `mmap` calls.
@@ -213,59 +663,27 @@ fn transform_prot(p_flags: ElfWord) -> libc::c_int {
     out
 }
 
-pub enum HugeError {
-    Unreadable,
-    Conflict,
-    Writable,
-    MemfdCreateFailed(i32),
-    FtruncateFailed(i32),
-    InitialMmapFailed(i32),
-    RemapFailed(i32),
-}
-
-impl std::fmt::Display for HugeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            HugeError::Unreadable => write!(f, "unreadable"),
-            HugeError::Conflict => write!(f, "conflicting mappings within all relevant huge pages"),
-            HugeError::Writable => write!(f, "writable"),
-            HugeError::MemfdCreateFailed(e) => {
-                write!(f, "memfd_create failed: {}", Error::from_raw_os_error(*e))
-            }
-            HugeError::FtruncateFailed(e) => {
-                write!(f, "ftruncate failed: {}", Error::from_raw_os_error(*e))
-            }
-            HugeError::InitialMmapFailed(e) => {
-                write!(f, "initial mmap failed: {}", Error::from_raw_os_error(*e))
-            }
-            HugeError::RemapFailed(e) => {
-                write!(f, "remap failed: {}", Error::from_raw_os_error(*e))
-            }
-        }
-    }
-}
-
 /// A reserved virtual address range (one mapped with no permissions).
 ///
 /// See [`Segment::remap`] to understand the purpose of the reservation.
-struct Reservation(Range<usize>);
+struct Reservation<'a> {
+    sys: &'a dyn Sys,
+    range: Range<usize>,
+}
 
-impl Reservation {
+impl<'a> Reservation<'a> {
     /// Try to reserve an address range. Will return `None`` on overlap with an existing mapping.
-    fn new(range: Range<usize>) -> Option<Self> {
-        match unsafe {
-            libc::mmap(
-                range.start as *mut libc::c_void,
-                range.len(),
-                libc::PROT_NONE,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE,
-                -1,
-                0,
-            )
-        } {
-            libc::MAP_FAILED => None,
-            r if r == range.start as *mut libc::c_void => Some(Self(range)),
-            o => {
+    unsafe fn new(sys: &'a dyn Sys, range: Range<usize>) -> Option<Self> {
+        match sys.mmap(
+            range.start,
+            range.len(),
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE,
+            -1,
+        ) {
+            Err(_) => None,
+            Ok(r) if r == range.start => Some(Self { sys, range }),
+            Ok(o) => {
                 // See mmap(2): "Note that older kernels which do not recognize
                 // the MAP_FIXED_NOREPLACE flag will typically (upon detecting a
                 // collision with a preexisting mapping) fall back to a
@@ -273,9 +691,7 @@ impl Reservation {
                 // that is different from the requested address.  Therefore,
                 // backward-compatible software should check
                 // the returned address against the requested address."
-                unsafe {
-                    libc::munmap(o, range.len());
-                }
+                sys.munmap(o..o + range.len());
                 None
             }
         }
@@ -284,11 +700,9 @@ impl Reservation {
 
 /// Drops a reservation; note the caller should `std::mem::forget` the reservation to prevent
 /// this when the reservation is claimed.
-impl Drop for Reservation {
+impl Drop for Reservation<'_> {
     fn drop(&mut self) {
-        unsafe {
-            libc::munmap(self.0.start as *mut libc::c_void, self.0.end - self.0.start);
-        }
+        unsafe { self.sys.munmap(self.range.clone()) }
     }
 }
 
@@ -301,69 +715,208 @@ impl Drop for Reservation {
 ///    potentially map something else in its place).
 /// 3. libc operations (some used here) will not write to this region.
 unsafe fn replace(
+    sys: &dyn Sys,
+    strategy: RemapStrategy,
     path: *const libc::c_char,
     map: Range<usize>,
     copy: Range<usize>,
     flags: ElfWord,
+    huge_page_mask: usize,
 ) -> Result<(), HugeError> {
     // copy should be within map.
     debug_assert!(copy.start >= map.start);
     debug_assert!(copy.end <= map.end);
 
-    let fd = memfd_create(path, libc::MFD_CLOEXEC | libc::MFD_HUGETLB);
-    if fd == -1 {
-        return Err(HugeError::MemfdCreateFailed(errno()));
+    match strategy {
+        RemapStrategy::HugetlbMemfd => {
+            replace_memfd(sys, path, map, copy, flags, true, huge_page_mask)
+        }
+        RemapStrategy::ShmemMemfd => {
+            replace_memfd(sys, path, map, copy, flags, false, huge_page_mask)
+        }
+        RemapStrategy::Collapse => unreachable!("Segment::remap collapses without replacing"),
+        RemapStrategy::AnonThp => replace_anon(sys, path, map, copy, flags, huge_page_mask),
     }
-    if libc::ftruncate(fd, map.len() as i64) == -1 {
-        let e = errno();
-        libc::close(fd);
+}
+
+/// Implements [`RemapStrategy::HugetlbMemfd`] and [`RemapStrategy::ShmemMemfd`] for
+/// [`replace`].
+unsafe fn replace_memfd(
+    sys: &dyn Sys,
+    path: *const libc::c_char,
+    map: Range<usize>,
+    copy: Range<usize>,
+    flags: ElfWord,
+    hugetlb: bool,
+    huge_page_mask: usize,
+) -> Result<(), HugeError> {
+    let memfd_flags = match hugetlb {
+        true => {
+            // Request the page size explicitly; the default hugetlb size may differ.
+            let size_log2 = (huge_page_mask + 1).trailing_zeros();
+            libc::MFD_CLOEXEC | libc::MFD_HUGETLB | (size_log2 << libc::MFD_HUGE_SHIFT)
+        }
+        false => libc::MFD_CLOEXEC,
+    };
+    let fd = sys
+        .memfd_create(path, memfd_flags)
+        .map_err(HugeError::MemfdCreateFailed)?;
+    if let Err(e) = sys.ftruncate(fd, map.len()) {
+        sys.close(fd);
         return Err(HugeError::FtruncateFailed(e));
     }
-    let tmp_addr = match libc::mmap(
-        std::ptr::null_mut(),
-        map.len(),
-        libc::PROT_READ | libc::PROT_WRITE,
-        libc::MAP_SHARED,
-        fd,
-        0,
-    ) {
-        libc::MAP_FAILED => {
-            let e = errno();
-            libc::close(fd);
+
+    // With shmem, huge pages are allocated when the copy below faults in pages, so the temporary
+    // mapping must be aligned and (for `shmem_enabled=advise`) advised. hugetlbfs handles both
+    // itself.
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let tmp_addr = match hugetlb {
+        true => sys.mmap(0, map.len(), prot, libc::MAP_SHARED, fd),
+        false => mmap_aligned(sys, map.len(), huge_page_mask, prot, libc::MAP_SHARED, fd),
+    };
+    let tmp_addr = match tmp_addr {
+        Ok(a) => a,
+        Err(e) => {
+            sys.close(fd);
             return Err(HugeError::InitialMmapFailed(e));
         }
-        a => a,
     };
-    let dst = copy
-        .start
-        .wrapping_add(tmp_addr as usize)
-        .wrapping_sub(map.start);
-    debug_assert!(dst >= tmp_addr as usize);
-    debug_assert!(dst + copy.len() <= tmp_addr as usize + map.len());
-    libc::memcpy(
-        dst as *mut libc::c_void,
-        copy.start as *const libc::c_void,
-        copy.len(),
-    );
-    libc::munmap(tmp_addr, map.len());
-    if libc::mmap(
-        map.start as *mut libc::c_void,
+    let tmp = tmp_addr..tmp_addr + map.len();
+    if !hugetlb {
+        if let Err(e) = sys.madvise(tmp.clone(), libc::MADV_HUGEPAGE) {
+            sys.munmap(tmp);
+            sys.close(fd);
+            return Err(HugeError::MadviseFailed(e));
+        }
+    }
+    let dst = copy.start.wrapping_add(tmp_addr).wrapping_sub(map.start);
+    debug_assert!(dst >= tmp_addr);
+    debug_assert!(dst + copy.len() <= tmp.end);
+    sys.copy(dst, copy.start, copy.len());
+    sys.munmap(tmp);
+    if let Err(e) = sys.mmap(
+        map.start,
         map.len(),
         transform_prot(flags),
         libc::MAP_PRIVATE | libc::MAP_FIXED,
         fd,
+    ) {
+        sys.close(fd);
+        return Err(HugeError::RemapFailed(e));
+    }
+    sys.close(fd);
+    if !hugetlb {
+        // The final mapping must also be advised for the kernel to map the page cache's huge
+        // pages with PMDs. The mapping is already in place, so failure here isn't worth undoing.
+        let _ = sys.madvise(map, libc::MADV_HUGEPAGE);
+    }
+    Ok(())
+}
+
+/// Creates a huge page-aligned mapping of `len` bytes, as in `mmap(NULL, len, prot, flags, fd,
+/// 0)`.
+///
+/// This over-reserves address space, maps within it, then trims the excess at either end.
+unsafe fn mmap_aligned(
+    sys: &dyn Sys,
+    len: usize,
+    huge_page_mask: usize,
+    prot: libc::c_int,
+    flags: libc::c_int,
+    fd: libc::c_int,
+) -> Result<usize, i32> {
+    let alloc_len = len + huge_page_mask + 1;
+    let alloc = sys.mmap(
         0,
-    ) == libc::MAP_FAILED
-    {
-        let e = errno();
-        libc::close(fd);
+        alloc_len,
+        libc::PROT_NONE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+        -1,
+    )?;
+    let addr = round_up(alloc, huge_page_mask);
+    if let Err(e) = sys.mmap(addr, len, prot, flags | libc::MAP_FIXED, fd) {
+        sys.munmap(alloc..alloc + alloc_len);
+        return Err(e);
+    }
+    if addr > alloc {
+        sys.munmap(alloc..addr);
+    }
+    let end = addr + len;
+    if alloc + alloc_len > end {
+        sys.munmap(end..alloc + alloc_len);
+    }
+    Ok(addr)
+}
+
+/// Implements [`RemapStrategy::AnonThp`] for [`replace`].
+unsafe fn replace_anon(
+    sys: &dyn Sys,
+    path: *const libc::c_char,
+    map: Range<usize>,
+    copy: Range<usize>,
+    flags: ElfWord,
+    huge_page_mask: usize,
+) -> Result<(), HugeError> {
+    // The temporary mapping must be huge page-aligned so the copy below faults in huge pages.
+    let tmp_addr = mmap_aligned(
+        sys,
+        map.len(),
+        huge_page_mask,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        -1,
+    )
+    .map_err(HugeError::InitialMmapFailed)?;
+    let tmp = tmp_addr..tmp_addr + map.len();
+    if let Err(e) = sys.madvise(tmp.clone(), libc::MADV_HUGEPAGE) {
+        sys.munmap(tmp);
+        return Err(HugeError::MadviseFailed(e));
+    }
+    sys.copy(tmp_addr + (copy.start - map.start), copy.start, copy.len());
+    if let Err(e) = sys.mprotect(tmp.clone(), transform_prot(flags)) {
+        sys.munmap(tmp);
+        return Err(HugeError::MprotectFailed(e));
+    }
+
+    // `mremap` atomically replaces the original mapping (which may include the code currently
+    // executing) and moves the huge page table entries intact.
+    if let Err(e) = sys.mremap_fixed(tmp_addr, map.len(), map.start) {
+        sys.munmap(tmp);
         return Err(HugeError::RemapFailed(e));
     }
-    libc::close(fd);
+    name_anon(sys, &map, path);
     Ok(())
 }
 
-impl Segment {
+/// Implements [`RemapStrategy::Collapse`]: collapses `range`, which must be huge page-aligned,
+/// into huge pages in place.
+unsafe fn collapse(sys: &dyn Sys, range: Range<usize>) -> Result<(), HugeError> {
+    sys.madvise(range, MADV_COLLAPSE)
+        .map_err(HugeError::CollapseFailed)
+}
+
+/// The maximum length of an anonymous mapping name, including the trailing NUL.
+const ANON_NAME_MAX: usize = 80;
+
+/// Names the anonymous mapping `map` after `path`, so `/proc/<pid>/maps` shows `[anon:<path>]`.
+///
+/// This is best-effort: it fails on kernels older than 5.17 or without `CONFIG_ANON_VMA_NAME`.
+/// Long paths are truncated from the start, and characters the kernel rejects are replaced.
+unsafe fn name_anon(sys: &dyn Sys, map: &Range<usize>, path: *const libc::c_char) {
+    let path = CStr::from_ptr(path).to_bytes();
+    let tail = &path[path.len().saturating_sub(ANON_NAME_MAX - 1)..];
+    let mut name = [0u8; ANON_NAME_MAX];
+    for (d, &s) in name.iter_mut().zip(tail) {
+        *d = match s {
+            b'[' | b']' | b'\\' | b'$' | b'`' => b'_',
+            0x20..=0x7e => s,
+            _ => b'_',
+        };
+    }
+    sys.name_anon(map.clone(), name.as_ptr());
+}
+
+impl<'a> Segment<'a> {
     /// Tries to remap as much of the entry as possible to do soundly.
     ///
     /// This attempts to "reserve" (create a memory mapping that will not
@@ -399,102 +952,390 @@ impl Segment {
     /// P = padding (within a remapped page)
     /// . = unmapped
     /// ```
+    ///
+    /// [`RemapStrategy::Collapse`] is the exception: it collapses in place only the huge pages
+    /// wholly within the segment (huge pages 2 and 3 above), with no reservations or copying.
+    ///
+    /// If [`RemapStrategy::AnonThp`] finds conflicting mappings within every relevant huge page,
+    /// it retries with each smaller size in `huge_page_masks` in turn.
     pub(crate) unsafe fn remap(
-        &mut self,
+        &self,
         base_page_mask: usize,
-        huge_page_mask: usize,
-    ) -> Result<Range<usize>, HugeError> {
+        huge_page_masks: &[usize],
+        large_page_mask: Option<usize>,
+        strategies: &[RemapStrategy],
+        report: &mut SegmentReport,
+    ) {
         if (self.flags & PF_R) == 0 {
             // If it's unreadable, it can't be copied. (And would remapping it be useful anyway?)
-            return Err(HugeError::Unreadable);
+            report.remap = Some(Err(HugeError::Unreadable));
+            return;
         }
         if (self.flags & PF_W) != 0 {
             // Can't trust that it won't change while we're copying it below.
-            return Err(HugeError::Writable);
+            report.remap = Some(Err(HugeError::Writable));
+            return;
         }
         let page_range =
             (self.addrs.start & !base_page_mask)..round_up(self.addrs.end, base_page_mask);
-
-        let hugepage_outer_range =
-            self.addrs.start & !huge_page_mask..round_up(self.addrs.end, huge_page_mask);
+        let huge_page_mask = huge_page_masks[0];
         let hugepage_inner_range =
             round_up(page_range.start, huge_page_mask)..page_range.end & !huge_page_mask;
-        let mut start_reservation = None;
-        let start = if hugepage_outer_range.start < page_range.start {
-            start_reservation = Reservation::new(hugepage_outer_range.start..page_range.start);
-            match start_reservation.is_some() {
-                true => hugepage_outer_range.start,
-                false => hugepage_inner_range.start,
-            }
-        } else {
-            hugepage_inner_range.start
-        };
-        let mut end_reservation = None;
-        let end = if hugepage_outer_range.end > page_range.end {
-            end_reservation = Reservation::new(page_range.end..hugepage_outer_range.end);
-            match end_reservation.is_some() {
-                true => hugepage_outer_range.end,
-                false => hugepage_inner_range.end,
+
+        for &strategy in strategies {
+            let result = match (strategy, large_page_mask) {
+                (RemapStrategy::Collapse, _) if hugepage_inner_range.is_empty() => {
+                    Err(HugeError::TooSmall)
+                }
+                (RemapStrategy::Collapse, _) => collapse(self.sys, hugepage_inner_range.clone())
+                    .map(|()| hugepage_inner_range.clone()),
+                (RemapStrategy::HugetlbMemfd, Some(large_page_mask)) => {
+                    self.remap_large(&page_range, huge_page_mask, large_page_mask, report)
+                }
+                (RemapStrategy::AnonThp, _) => {
+                    self.remap_mthp(&page_range, huge_page_masks, report)
+                }
+                _ => self.replace_pages(strategy, &page_range, huge_page_mask),
+            };
+
+            // Record any previous failure as a fallback; the latest result is the final one.
+            if let (Some(prev_strategy), Some(Err(prev))) =
+                (report.remap_strategy, report.remap.take())
+            {
+                report.remap_fallbacks.push(prev_strategy, prev);
             }
-        } else {
-            hugepage_inner_range.end
-        };
-        if start >= end {
-            return Err(HugeError::Conflict);
-        }
-        let copy = std::cmp::max(start, page_range.start)..std::cmp::min(end, page_range.end);
-        let path = &self.path[0] as *const u8 as *const libc::c_char;
-        match replace(path, start..end, copy, self.flags) {
-            Ok(()) => {
-                std::mem::forget(start_reservation);
-                std::mem::forget(end_reservation);
-                Ok(start..end)
+            let succeeded = result.is_ok();
+            report.remap = Some(result);
+            report.remap_strategy = Some(strategy);
+            if succeeded {
+                return;
             }
-            Err(e) => Err(e),
         }
     }
-}
 
-fn log_maps(when: &'static str, log: &mut Vec<(log::Level, String)>) {
-    // `/proc/self/maps`` might be useful for debugging. But take the logged version below with a
-    // grain of salt because mappings might change due to the logging's own memory allocations.
-    log.push((
-        log::Level::Trace,
-        match std::fs::read("/proc/self/maps") {
-            Ok(maps) => format!("maps {when}:\n{}", String::from_utf8_lossy(&maps[..])),
-            Err(e) => format!("couldn't read maps: {e}"),
-        },
-    ));
-}
+    /// Remaps `pages` (a portion of this segment) with `strategy` at `huge_page_mask`
+    /// granularity, using reservations to cover partial huge pages at either end if possible.
+    unsafe fn replace_pages(
+        &self,
+        strategy: RemapStrategy,
+        pages: &Range<usize>,
+        huge_page_mask: usize,
+    ) -> Result<Range<usize>, HugeError> {
+        let reserved = self.reserve(pages, huge_page_mask);
+        let Some(Replacement { map, copy, .. }) = reserved.replacement.clone() else {
+            return Err(HugeError::Conflict);
+        };
+        replace(
+            self.sys,
+            strategy,
+            self.path,
+            map.clone(),
+            copy,
+            self.flags,
+            huge_page_mask,
+        )?;
+        reserved.claim();
+        Ok(map)
+    }
 
-pub(crate) fn run(options: super::Options) -> Output {
-    let mut log = Vec::new();
-    log_maps("before", &mut log);
+    /// Remaps with [`RemapStrategy::AnonThp`] at the largest size in `huge_page_masks` which
+    /// doesn't conflict, recording any smaller size in `report`.
+    unsafe fn remap_mthp(
+        &self,
+        pages: &Range<usize>,
+        huge_page_masks: &[usize],
+        report: &mut SegmentReport,
+    ) -> Result<Range<usize>, HugeError> {
+        let mut result = Err(HugeError::Conflict);
+        for (i, &mask) in huge_page_masks.iter().enumerate() {
+            result = self.replace_pages(RemapStrategy::AnonThp, pages, mask);
+            if result != Err(HugeError::Conflict) {
+                report.mthp_size = (i > 0 && result.is_ok()).then_some(mask + 1);
+                break;
+            }
+        }
+        result
+    }
 
-    // This function replaces portions of the memory map referring to program text. It assumes
-    // nothing else is changing them, for example by `dlopen(3)` and `dlclose(3)` calls. That
-    // assumption can't be verified if there are other threads running.
-    match num_threads::num_threads() {
-        Some(t) if t.get() == 1 => {}
-        Some(t) => {
-            log.push((
-                log::Level::Warn,
-                format!("Skipping page priming: there are {t} threads running; must be 1!"),
-            ));
-            return Output { log };
+    /// Remaps with [`RemapStrategy::HugetlbMemfd`], using large (e.g. 1 GiB) pages for the
+    /// portion of `pages` they can cover wholly and regular huge pages for the head and tail.
+    ///
+    /// Large pages never extend outside the segment, so they add no padding; padding is added
+    /// only at the head and tail, as in [`Segment::replace_pages`]. If large pages can't be used,
+    /// records why in [`SegmentReport::remap_fallbacks`] and falls back to regular huge pages for
+    /// the whole segment.
+    unsafe fn remap_large(
+        &self,
+        pages: &Range<usize>,
+        huge_page_mask: usize,
+        large_page_mask: usize,
+        report: &mut SegmentReport,
+    ) -> Result<Range<usize>, HugeError> {
+        let large = round_up(pages.start, large_page_mask)..pages.end & !large_page_mask;
+        if large.is_empty() {
+            return self.replace_pages(RemapStrategy::HugetlbMemfd, pages, huge_page_mask);
         }
-        None => {
-            log.push((
-                log::Level::Warn,
-                "Skipping page priming: unable to get thread count!".to_owned(),
-            ));
-            return Output { log };
+        if let Err(e) = replace(
+            self.sys,
+            RemapStrategy::HugetlbMemfd,
+            self.path,
+            large.clone(),
+            large.clone(),
+            self.flags,
+            large_page_mask,
+        ) {
+            report.remap_fallbacks.push(RemapStrategy::HugetlbMemfd, e);
+            return self.replace_pages(RemapStrategy::HugetlbMemfd, pages, huge_page_mask);
         }
+        report.large_pages = Some(large.clone());
+
+        // The head and tail are optional; failure just leaves them in base pages.
+        let start = match pages.start < large.start {
+            true => self
+                .replace_pages(
+                    RemapStrategy::HugetlbMemfd,
+                    &(pages.start..large.start),
+                    huge_page_mask,
+                )
+                .map_or(large.start, |r| r.start),
+            false => large.start,
+        };
+        let end = match large.end < pages.end {
+            true => self
+                .replace_pages(
+                    RemapStrategy::HugetlbMemfd,
+                    &(large.end..pages.end),
+                    huge_page_mask,
+                )
+                .map_or(large.end, |r| r.end),
+            false => large.end,
+        };
+        Ok(start..end)
     }
 
-    let huge_page_mask = if options.remap {
+    /// Reserves the free portions of the huge pages surrounding `pages`.
+    ///
+    /// The planned replacement is the widest huge page-aligned range that can be replaced: it
+    /// includes the partial huge pages at either end iff their reservations succeeded.
+    unsafe fn reserve(&self, pages: &Range<usize>, huge_page_mask: usize) -> Reserved<'a> {
+        let mut reservations = [None, None];
+        let mut next = 0;
+        let replacement = plan_replacement(pages, huge_page_mask, |range| {
+            reservations[next] = Reservation::new(self.sys, range);
+            next += 1;
+            reservations[next - 1].is_some()
+        });
+        Reserved {
+            replacement,
+            reservations,
+        }
+    }
+}
+
+/// The result of [`Segment::reserve`].
+struct Reserved<'a> {
+    /// The replacement to perform; `None` if no whole huge page is available.
+    replacement: Option<Replacement>,
+    reservations: [Option<Reservation<'a>>; 2],
+}
+
+impl Reserved<'_> {
+    /// Keeps the reservations' address space, which is now part of the replaced mapping.
+    fn claim(self) {
+        std::mem::forget(self.reservations);
+    }
+}
+
+/// Context pointer for `plan_cb`.
+///
+/// Unlike `phdr_cb`, `plan_cb` doesn't change the memory map, so it may allocate.
+struct PlanContext {
+    program_name: OsString,
+    objects: Vec<ObjectReport>,
+
+    /// The key of each of `objects`.
+    keys: Vec<ObjectKey>,
+    segments: Vec<(usize, ElfWord, Range<usize>)>,
+}
+
+impl PlanContext {
+    fn new() -> Self {
+        PlanContext {
+            program_name: program_name(),
+            objects: Vec::new(),
+            keys: Vec::new(),
+            segments: Vec::new(),
+        }
+    }
+
+    /// Records all loaded objects and their segments.
+    fn take() -> Self {
+        let mut ctx = PlanContext::new();
+        unsafe {
+            libc::dl_iterate_phdr(
+                Some(plan_cb),
+                &mut ctx as *mut PlanContext as *mut libc::c_void,
+            )
+        };
+        ctx
+    }
+}
+
+/// Callback supplied to `dl_iterate_phdr` to record objects and `PT_LOAD` segments for planning.
+///
+/// Must not panic due to the FFI boundary.
+unsafe extern "C" fn plan_cb(
+    info: *mut libc::dl_phdr_info,
+    _size: libc::size_t,
+    data: *mut libc::c_void,
+) -> libc::c_int {
+    if std::panic::catch_unwind(|| unsafe {
+        plan_cb_inner(&*info, &mut *(data as *mut PlanContext))
+    })
+    .is_err()
+    {
+        eprintln!("Aborting due to plan_cb failure.");
+        std::process::abort();
+    }
+    0
+}
+
+unsafe fn plan_cb_inner(info: &libc::dl_phdr_info, ctx: &mut PlanContext) {
+    let index = ctx.objects.len();
+    let path = match index {
+        0 => PathBuf::from(ctx.program_name.clone()),
+        _ => OsStr::from_bytes(unsafe { CStr::from_ptr(info.dlpi_name) }.to_bytes()).into(),
+    };
+    ctx.objects.push(ObjectReport {
+        index,
+        path,
+        load_bias: info.dlpi_addr as usize,
+        is_main: index == 0,
+    });
+    ctx.keys.push(object_key(info));
+    let segs = unsafe { std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize) };
+    for seg in segs.iter().filter(|s| s.p_type == libc::PT_LOAD) {
+        let vaddr = info.dlpi_addr.wrapping_add(seg.p_vaddr) as usize;
+        ctx.segments
+            .push((index, seg.p_flags, vaddr..vaddr + seg.p_memsz as usize));
+    }
+}
+
+/// Implements [`crate::Options::plan`].
+pub(crate) fn plan(options: &super::Options) -> Plan {
+    let mut log = Vec::new();
+    let config = remap_config(options, &mut log);
+    let ctx = PlanContext::take();
+    let actions =
+        (!options.filters.is_empty()).then(|| filter_actions(&options.filters, &ctx, Some(0)));
+    let budget = options.budget.and_then(|b| resolve_budget(b, &mut log));
+    let allowed =
+        budget.map(|b| select_within_budget(options, &config, &ctx, None, actions.as_deref(), b));
+    let huge_page_sizes = config.huge_page_sizes();
+    let planner = config.planner(&huge_page_sizes, options.mlock);
+    let mut segments = plan_segments(
+        &planner,
+        ctx.segments,
+        |addrs| segment_action(allowed.as_deref(), actions.as_deref(), addrs.start),
+        smaps::read_maps().ok(),
+    );
+    for seg in &mut segments {
+        let action = actions.as_ref().map(|a| action_at(a, seg.addrs.start));
+        seg.deprioritized = is_deprioritized(allowed.as_deref(), action, seg.addrs.start);
+    }
+    Plan {
+        base_page_size: planner.base_page_size,
+        huge_page_size: config.huge_page_size,
+        large_page_size: config.large_page_size,
+        mthp_sizes: config.mthp_sizes,
+        remap_strategies: config.remap_strategies,
+        budget,
+        objects: ctx.objects,
+        segments,
+    }
+}
+
+/// Plans each of `segments` with `planner`, restricted by each one's `action`.
+///
+/// `occupied` is the memory map, to which remapped ranges are added as they're planned. If it
+/// couldn't be read, nothing is conservatively assumed free.
+fn plan_segments(
+    planner: &Planner<'_>,
+    segments: impl IntoIterator<Item = (usize, ElfWord, Range<usize>)>,
+    action: impl Fn(&Range<usize>) -> Action,
+    mut occupied: Option<Vec<Range<usize>>>,
+) -> Vec<SegmentPlan> {
+    segments
+        .into_iter()
+        .map(|(object_i, flags, addrs)| {
+            let action = action(&addrs);
+            let planner = Planner {
+                strategy: planner.strategy.filter(|_| action.remap),
+                mlock: planner.mlock && action.mlock,
+                ..*planner
+            };
+            let seg = planner.plan(object_i, flags, addrs, |r| match &occupied {
+                Some(o) => o.iter().all(|o| o.end <= r.start || r.end <= o.start),
+                None => false,
+            });
+            if let (Some(o), Some(r)) = (occupied.as_mut(), seg.remapped()) {
+                o.push(r.clone());
+            }
+            seg
+        })
+        .collect()
+}
+
+fn log_maps(when: &'static str, log: &mut Vec<(log::Level, String)>) {
+    // `/proc/self/maps`` might be useful for debugging. But take the logged version below with a
+    // grain of salt because mappings might change due to the logging's own memory allocations.
+    log.push((
+        log::Level::Trace,
+        match std::fs::read("/proc/self/maps") {
+            Ok(maps) => format!("maps {when}:\n{}", String::from_utf8_lossy(&maps[..])),
+            Err(e) => format!("couldn't read maps: {e}"),
+        },
+    ));
+}
+
+/// The remapping configuration, after probing the kernel.
+struct RemapConfig {
+    /// The huge page size, iff remapping should be performed.
+    huge_page_size: Option<usize>,
+    remap_strategies: Vec<RemapStrategy>,
+    mthp_sizes: Vec<usize>,
+    large_page_size: Option<usize>,
+}
+
+impl RemapConfig {
+    /// Returns the usable huge page sizes, largest first, for [`RemapConfig::planner`].
+    fn huge_page_sizes(&self) -> Vec<usize> {
+        self.huge_page_size
+            .into_iter()
+            .chain(self.mthp_sizes.iter().copied())
+            .collect()
+    }
+
+    /// Returns a planner for this configuration, with `huge_page_sizes` from
+    /// [`RemapConfig::huge_page_sizes`].
+    fn planner<'a>(&self, huge_page_sizes: &'a [usize], mlock: bool) -> Planner<'a> {
+        Planner {
+            base_page_size: base_page_size(),
+            huge_page_sizes,
+            large_page_size: self.large_page_size,
+            strategy: self
+                .huge_page_size
+                .and(self.remap_strategies.first().copied()),
+            mlock,
+        }
+    }
+}
+
+/// Determines the remapping configuration for `options`, logging any problems.
+fn remap_config(options: &super::Options, log: &mut Vec<(log::Level, String)>) -> RemapConfig {
+    let huge_page_size = if options.remap {
         match huge_page_size() {
-            Ok(Some(s)) => Some(mask(s)),
+            Ok(Some(s)) => Some(s),
             Ok(None) => {
                 log.push((
                     log::Level::Warn,
@@ -514,80 +1355,995 @@ pub(crate) fn run(options: super::Options) -> Output {
         None
     };
 
-    if huge_page_mask.is_none() && !options.mlock {
+    // Probe before touching memory, so strategies which definitely won't work are skipped rather
+    // than attempted on every segment.
+    let probe = huge_page_size.map(probe::Probe::new);
+    let remap_strategies: Vec<RemapStrategy> = match &probe {
+        Some(probe) => options
+            .effective_remap_strategies()
+            .iter()
+            .copied()
+            .filter(|&strategy| match probe.check(strategy) {
+                Ok(()) => true,
+                Err(reason) => {
+                    log.push((
+                        log::Level::Info,
+                        format!("Skipping remap strategy {strategy}: {reason}."),
+                    ));
+                    false
+                }
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    let huge_page_size = match remap_strategies.is_empty() {
+        true if huge_page_size.is_some() => {
+            log.push((
+                log::Level::Warn,
+                "Huge page remapping requested but no remap strategy is available.".to_owned(),
+            ));
+            None
+        }
+        _ => huge_page_size,
+    };
+
+    // Smaller mTHP sizes are only usable with anonymous THP.
+    let mthp_sizes = match (huge_page_size, &probe) {
+        (Some(s), Some(probe)) if remap_strategies.contains(&RemapStrategy::AnonThp) => {
+            probe.anon_mthp_sizes(s)
+        }
+        _ => Vec::new(),
+    };
+
+    let large_page_size = match (options.hugetlb_page_size, huge_page_size) {
+        (Some(large), Some(huge)) if remap_strategies.contains(&RemapStrategy::HugetlbMemfd) => {
+            match probe::hugetlb_pool(large) {
+                _ if !large.is_power_of_two() || large <= huge => {
+                    log.push((
+                        log::Level::Warn,
+                        format!(
+                            "Ignoring hugetlb page size {large}: must be a power of two larger \
+                             than {huge}."
+                        ),
+                    ));
+                    None
+                }
+                None | Some((0, 0)) => {
+                    log.push((
+                        log::Level::Warn,
+                        format!(
+                            "Ignoring hugetlb page size {large}: no unreserved pages of this size."
+                        ),
+                    ));
+                    None
+                }
+                Some(_) => Some(large),
+            }
+        }
+        _ => None,
+    };
+
+    RemapConfig {
+        huge_page_size,
+        remap_strategies,
+        mthp_sizes,
+        large_page_size,
+    }
+}
+
+pub(crate) fn run(mut options: super::Options) -> Output {
+    let mut log = Vec::new();
+    let mut report = Report {
+        pid: std::process::id(),
+        ..Default::default()
+    };
+    log_maps("before", &mut log);
+
+    // Hold the lock throughout, so concurrent runs don't both prime the same new objects.
+    let snapshot = Snapshot::take();
+    let mut seen = SEEN.lock().unwrap_or_else(PoisonError::into_inner);
+    let only: Option<Vec<ObjectKey>> = match (options.new_objects_only, seen.as_ref()) {
+        (true, Some(prev)) if snapshot.unchanged_since(prev) => Some(Vec::new()),
+        (true, Some(prev)) => Some(snapshot.new_since(prev)),
+        _ => None,
+    };
+    let (objects, loads, mut load_bytes) = snapshot
+        .objects
+        .iter()
+        .filter(|(key, _, _)| only.as_ref().map_or(true, |o| o.contains(key)))
+        .fold((0, 0, 0), |(objects, loads, total), &(_, l, bytes)| {
+            (objects + 1, loads + l, total + bytes)
+        });
+    if objects == 0 {
+        *seen = Some(snapshot);
+        log.push((log::Level::Debug, "No new objects to prime.".to_owned()));
+        report.skipped = Some(Skipped::NoNewObjects);
+        return Output { log, report };
+    }
+
+    // Remapping replaces portions of the memory map referring to program text. It assumes
+    // nothing else is changing them, for example by `dlopen(3)` and `dlclose(3)` calls. That
+    // assumption can't be verified if there are other threads running. Locking has no such
+    // requirement.
+    let mut threads_running = match num_threads::num_threads() {
+        Some(t) if t.get() == 1 => None,
+        Some(t) => Some(Skipped::ThreadsRunning(t.get())),
+        None => Some(Skipped::ThreadCountUnavailable),
+    };
+    let mut stopper = None;
+    if let (Some(s), true) = (threads_running, options.remap && options.stop_the_world) {
+        let threads = match s {
+            Skipped::ThreadsRunning(t) => t,
+            _ => 0,
+        };
+        match stw::Stopper::new(threads) {
+            Ok(st) => {
+                stopper = Some(st);
+                threads_running = None;
+            }
+            Err(e) => threads_running = Some(e),
+        }
+    }
+    if let Some(s) = threads_running {
+        if !options.mlock {
+            log.push((log::Level::Warn, format!("Skipping page priming: {s}!")));
+            report.skipped = Some(s);
+            return Output { log, report };
+        }
+        if options.remap {
+            log.push((
+                log::Level::Warn,
+                format!("Skipping huge page remapping: {s}! Locking only."),
+            ));
+            report.remap_skipped = Some(s);
+            options.remap = false;
+        }
+    }
+
+    let config = remap_config(&options, &mut log);
+    if config.huge_page_size.is_none() && !options.mlock {
         log.push((
             log::Level::Warn,
             "No page priming operations to perform.".to_owned(),
         ));
-        return Output { log };
+        report.skipped = Some(Skipped::NothingToDo);
+        return Output { log, report };
     }
+    let budget = options.budget.and_then(|b| resolve_budget(b, &mut log));
+    let (mut allowed, mut actions) = (None, None);
+    let remap = config.huge_page_size.is_some();
+    if budget.is_some() || !options.filters.is_empty() || (options.mlock && remap) {
+        let walk = PlanContext::take();
+        if !options.filters.is_empty() {
+            actions = Some(filter_actions(&options.filters, &walk, Some(0)));
+        }
+        if let Some(budget) = budget {
+            allowed = Some(select_within_budget(
+                &options,
+                &config,
+                &walk,
+                only.as_deref(),
+                actions.as_deref(),
+                budget,
+            ));
+            report.budget = Some(budget);
+        }
 
-    let mut ctx = Context {
-        mlock: options.mlock,
-        base_page_mask: mask(base_page_size()),
-        huge_page_mask,
-        next_object_i: 0,
-        program_name: program_name(),
-        segments: Vec::with_capacity(1024),
-    };
+        // The snapshot counts whole base pages of every segment. Instead count exactly what will
+        // be locked: remapped segments in whole huge pages, and nothing filtered or over budget.
+        if options.mlock {
+            load_bytes = lock_estimate(&config, &walk, only.as_deref(), |addrs| {
+                segment_action(allowed.as_deref(), actions.as_deref(), addrs.start)
+            });
+        }
+    }
+    if options.mlock {
+        report.memlock = Some(memlock::preflight(options.lock_mode, load_bytes, &mut log));
+    }
+    let mut ctx = Context::new(&Real, &options, &config, only, (objects, loads));
+    ctx.allowed = allowed;
+    ctx.actions = actions;
+    let stopper = stopper.filter(|_| config.huge_page_size.is_some());
+    if stopper.is_some() {
+        // Lock once other threads resume, so they're stopped only while remapping.
+        if let Some(mode) = ctx.mlock.take() {
+            ctx.deferred_mlock = Some(mode);
+        }
+    }
 
     // This is where the work actually happens.
-    unsafe { libc::dl_iterate_phdr(Some(phdr_cb), &mut ctx as *mut Context as *mut libc::c_void) };
+    let block = SignalBlock::new();
+    let mut stop_failed = None;
+    match stopper {
+        Some(mut stopper) => {
+            // A thread stopped while holding the loader's lock would deadlock `dl_iterate_phdr`,
+            // so list the objects first, then stop the world and walk that list.
+            let infos = loaded_infos();
+            let stopped = stopper.stop().and_then(|()| {
+                stw::check_objects(&infos).map_err(|e| {
+                    stopper.resume();
+                    e
+                })
+            });
+            match stopped {
+                Ok(()) => {
+                    for info in &infos {
+                        unsafe { phdr_cb_inner(info, &mut ctx) };
+                    }
+                    if let Some((d, threads)) = stopper.resume() {
+                        log.push((
+                            log::Level::Info,
+                            format!("Stopped {threads} other threads for {d:?} while remapping."),
+                        ));
+                        report.world_stopped = Some(d);
+                    }
+                }
+                Err(e) => {
+                    ctx.huge_page_masks.clear();
+                    stop_failed = Some(e);
+                    walk(&mut ctx);
+                }
+            }
+        }
+        None => walk(&mut ctx),
+    }
+    record_deferred(block.unblock(), &mut report, &mut log);
+    if let Some(s) = stop_failed {
+        log.push((
+            log::Level::Warn,
+            format!("Skipping huge page remapping: {s}! Locking only."),
+        ));
+        report.remap_skipped = Some(s);
+    }
+    *seen = Some(snapshot);
+    drop(seen);
+
+    finish(&options, config, ctx, report, log, true)
+}
+
+/// Primes the objects described by `infos`; see [`crate::Options::run_objects`].
+pub(crate) unsafe fn run_objects(options: super::Options, infos: &[libc::dl_phdr_info]) -> Output {
+    unsafe { run_objects_with(&Real, main_bias(), options, infos) }
+}
+
+/// Implements [`run_objects`] via `sys`, taking the object with an empty name and load bias
+/// `main_bias` to be the main executable.
+///
+/// Other objects may have an empty name too, such as the vDSO.
+unsafe fn run_objects_with(
+    sys: &dyn Sys,
+    main_bias: usize,
+    options: super::Options,
+    infos: &[libc::dl_phdr_info],
+) -> Output {
+    let mut log = Vec::new();
+    let mut report = Report {
+        pid: std::process::id(),
+        ..Default::default()
+    };
+    let config = remap_config(&options, &mut log);
+    if config.huge_page_size.is_none() && !options.mlock {
+        log.push((
+            log::Level::Warn,
+            "No page priming operations to perform.".to_owned(),
+        ));
+        report.skipped = Some(Skipped::NothingToDo);
+        return Output { log, report };
+    }
+    let is_main = |info: &libc::dl_phdr_info| {
+        info.dlpi_addr as usize == main_bias && unsafe { CStr::from_ptr(info.dlpi_name) }.is_empty()
+    };
+    let loads = infos.iter().map(|i| unsafe { load_sizes(i) }.0).sum();
+
+    // `phdr_cb_inner` substitutes the main executable's path for its empty name.
+    let mut walk = PlanContext::new();
+    for info in infos {
+        let index = walk.objects.len();
+        unsafe { plan_cb_inner(info, &mut walk) };
+        let name = unsafe { CStr::from_ptr(info.dlpi_name) };
+        let object = &mut walk.objects[index];
+        object.is_main = is_main(info);
+        object.path = match object.is_main {
+            true => PathBuf::from(walk.program_name.clone()),
+            false => OsStr::from_bytes(name.to_bytes()).into(),
+        };
+    }
+    let main = walk.objects.iter().position(|o| o.is_main);
+    let actions =
+        (!options.filters.is_empty()).then(|| filter_actions(&options.filters, &walk, main));
+    if options.mlock {
+        let needed = lock_estimate(&config, &walk, None, |addrs| {
+            segment_action(None, actions.as_deref(), addrs.start)
+        });
+        report.memlock = Some(memlock::preflight(options.lock_mode, needed, &mut log));
+    }
+    let mut ctx = Context::new(sys, &options, &config, None, (infos.len(), loads));
+    ctx.actions = actions;
+    let block = SignalBlock::new();
+    for info in infos {
+        // `phdr_cb_inner` takes the first object walked to be the main executable.
+        ctx.walked = usize::from(!is_main(info));
+        unsafe { phdr_cb_inner(info, &mut ctx) };
+    }
+    record_deferred(block.unblock(), &mut report, &mut log);
+    finish(&options, config, ctx, report, log, false)
+}
+
+impl<'a> Context<'a> {
+    /// Returns a context with room for `counts` objects and segments.
+    fn new(
+        sys: &'a dyn Sys,
+        options: &super::Options,
+        config: &RemapConfig,
+        only: Option<Vec<ObjectKey>>,
+        counts: (usize, usize),
+    ) -> Self {
+        Context {
+            sys,
+            mlock: Some(options.lock_mode)
+                .filter(|m| options.mlock && !options.background_lock && !m.is_process_wide()),
+            deferred_mlock: Some(options.lock_mode)
+                .filter(|m| options.mlock && !options.background_lock && m.is_process_wide()),
+            base_page_mask: mask(base_page_size()),
+            huge_page_masks: config
+                .huge_page_size
+                .into_iter()
+                .chain(config.mthp_sizes.iter().copied())
+                .map(mask)
+                .collect(),
+            remap_strategies: config.remap_strategies.clone(),
+            large_page_mask: config.large_page_size.map(mask),
+            only,
+            allowed: None,
+            actions: None,
+            walked: 0,
+            next_object_i: 0,
+            program_name: program_name(),
+            objects: Vec::with_capacity(counts.0),
+            segments: Vec::with_capacity(counts.1),
+            segments_dropped: 0,
+        }
+    }
+}
+
+/// Blocks all blockable signals on this thread until [`SignalBlock::unblock`].
+///
+/// While remapping, reservations occupy address space and segments are briefly replaced. A
+/// handler running meanwhile (e.g. a `SIGPROF`-based profiler or a crash handler which walks
+/// loaded objects) would observe that half-primed state.
+struct SignalBlock {
+    old: libc::sigset_t,
+}
+
+impl SignalBlock {
+    fn new() -> Self {
+        let mut all: libc::sigset_t = unsafe { std::mem::zeroed() };
+        let mut old: libc::sigset_t = unsafe { std::mem::zeroed() };
+        unsafe {
+            libc::sigfillset(&mut all);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &all, &mut old);
+        }
+        SignalBlock { old }
+    }
+
+    /// Restores the previous mask, returning the newly blocked signals which arrived meanwhile.
+    /// They're delivered as the mask is restored.
+    fn unblock(self) -> Vec<i32> {
+        let mut pending: libc::sigset_t = unsafe { std::mem::zeroed() };
+        unsafe { libc::sigpending(&mut pending) };
+        let deferred = (1..=libc::SIGRTMAX())
+            .filter(|&s| unsafe {
+                libc::sigismember(&pending, s) == 1 && libc::sigismember(&self.old, s) == 0
+            })
+            .collect();
+        unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &self.old, std::ptr::null_mut()) };
+        deferred
+    }
+}
+
+/// Records signals deferred by a [`SignalBlock`].
+fn record_deferred(signals: Vec<i32>, report: &mut Report, log: &mut Vec<(log::Level, String)>) {
+    if !signals.is_empty() {
+        log.push((
+            log::Level::Info,
+            format!("Deferred signals {signals:?} until priming finished."),
+        ));
+    }
+    report.signals_deferred = signals;
+}
+
+/// Fills in `report` from a completed walk and logs it, along with the maps iff `log_after`.
+fn finish(
+    options: &super::Options,
+    config: RemapConfig,
+    ctx: Context<'_>,
+    mut report: Report,
+    mut log: Vec<(log::Level, String)>,
+    log_after: bool,
+) -> Output {
+    let RemapConfig {
+        huge_page_size,
+        remap_strategies,
+        mthp_sizes,
+        large_page_size,
+    } = config;
+    report.base_page_size = ctx.base_page_mask + 1;
+    report.huge_page_size = huge_page_size;
+    report.remap_strategies = remap_strategies;
+    report.large_page_size = large_page_size;
+    report.mthp_sizes = mthp_sizes;
+    report.objects = ctx
+        .objects
+        .iter()
+        .enumerate()
+        .map(|(index, o)| ObjectReport {
+            index,
+            path: OsStr::from_bytes(
+                CStr::from_bytes_until_nul(&o.path)
+                    .expect("path has NUL")
+                    .to_bytes(),
+            )
+            .into(),
+            load_bias: o.load_bias,
+            is_main: o.is_main,
+        })
+        .collect();
+    let (sys, deferred_mlock) = (ctx.sys, ctx.deferred_mlock);
+    report.segments = ctx.segments;
+    report.segments_dropped = ctx.segments_dropped;
+    if report.segments_dropped > 0 {
+        log.push((
+            log::Level::Warn,
+            format!(
+                "{} segments were primed but not recorded; loaded objects changed while priming.",
+                report.segments_dropped
+            ),
+        ));
+    }
+    if let Some(budget) = report.budget {
+        let (n, bytes) = report
+            .deprioritized_segments()
+            .fold((0, 0), |(n, bytes), s| {
+                (n + 1, bytes + s.page_range(report.base_page_size).len())
+            });
+        if n > 0 {
+            log.push((
+                log::Level::Info,
+                format!(
+                    "Deprioritized {n} segments ({bytes} bytes) to fit within the budget of \
+                     {budget} bytes."
+                ),
+            ));
+        }
+    }
+
+    // Lock once remapping is done and any stopped threads have resumed.
+    if let Some(mode) = deferred_mlock {
+        lock_segments(sys, mode, &mut report, &AtomicUsize::new(0));
+    }
+
+    // With background locking, this happens once locking is done.
+    if !(options.background_lock && options.mlock) {
+        summarize(options.verify, &mut report, &mut log);
+        if log_after {
+            log_maps("after", &mut log);
+        }
+    }
+    Output { log, report }
+}
+
+/// Accounts for locked memory, verifies `report` if requested, then logs it.
+pub(crate) fn summarize(verify: bool, report: &mut Report, log: &mut Vec<(log::Level, String)>) {
+    memlock::account(report, log);
+    if verify {
+        smaps::verify(report, log);
+    }
 
     // Create a nice log message for debugging.
-    let mut msg = String::with_capacity(128 * ctx.segments.len());
-    msg.push_str("primed pages:\n");
-    let mut last_object_i = None;
-    for obj in &mut ctx.segments {
-        if Some(obj.object_i) != last_object_i {
-            let path = CStr::from_bytes_until_nul(&obj.path).expect("path has NUL");
-            let _ = writeln!(&mut msg, "object {}:", &path.to_string_lossy());
-        }
-        let _ = write!(
-            &mut msg,
-            "* {:012x}-{:012x} {} ->",
-            obj.addrs.start,
-            obj.addrs.end,
-            debug_prot(obj.flags)
-        );
+    log.push((log::Level::Info, report.to_string()));
+}
 
-        #[cfg(target_os = "linux")]
-        match obj.remap.as_ref() {
-            Some(Ok(remapped)) => {
-                let _ = write!(
-                    &mut msg,
-                    " remap={:012x}-{:012x}",
-                    remapped.start, remapped.end
-                );
-            }
-            Some(Err(e)) => {
-                let _ = write!(&mut msg, " remap={}", e);
-            }
-            None => {}
+/// Locks each segment in `report` after the walk, adding the bytes locked (or attempted) to
+/// `done` as it goes.
+///
+/// This is used by [`crate::Options::run_background`], by process-wide modes, where a single
+/// `mlockall` covers every segment, and after stopping the world, so locking happens once other
+/// threads have resumed.
+pub(crate) fn lock_segments(
+    sys: &dyn Sys,
+    mode: LockMode,
+    report: &mut Report,
+    done: &AtomicUsize,
+) {
+    let all = match mode {
+        LockMode::All => Some(unsafe { sys.mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) }),
+        LockMode::AllOnFault => {
+            Some(unsafe { sys.mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE | libc::MCL_ONFAULT) })
         }
-        match obj.mlock.as_ref() {
-            Some(Ok(())) => {
-                let _ = write!(&mut msg, " mlock=success");
-            }
-            Some(Err(e)) => {
-                let _ = write!(&mut msg, " mlock={}", Error::from_raw_os_error(*e));
-            }
-            None => {}
+        _ => None,
+    };
+    for seg in &mut report.segments {
+        if !seg.lock_wanted() && all.is_none() {
+            continue;
+        }
+        let range = seg.lock_range(report.base_page_size);
+        let len = range.len();
+        seg.mlock = Some(match all {
+            Some(r) => r,
+            None => unsafe { sys.mlock(range, mode == LockMode::OnFault) },
+        });
+        seg.mlock_mode = Some(mode);
+        if seg.lock_wanted() {
+            done.fetch_add(len, Ordering::Relaxed);
         }
-        msg.push('\n');
-        last_object_i = Some(obj.object_i);
     }
-    log.push((log::Level::Info, msg));
-    log_maps("after", &mut log);
-    Output { log }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_memory_max() {
+        assert_eq!(parse_memory_max("max\n").unwrap(), None);
+        assert_eq!(parse_memory_max("1073741824\n").unwrap(), Some(1 << 30));
+        assert!(parse_memory_max("lots").is_err());
+    }
+
+    #[test]
+    fn new_since() {
+        let prev = Snapshot {
+            adds: Some(3),
+            subs: Some(0),
+            objects: vec![((0, 1), 1, 0), ((10, 11), 1, 0), ((20, 21), 1, 0)],
+        };
+
+        // One object loaded at a new address.
+        let mut cur = Snapshot {
+            adds: Some(4),
+            subs: Some(0),
+            objects: vec![
+                ((0, 1), 1, 0),
+                ((10, 11), 1, 0),
+                ((20, 21), 1, 0),
+                ((30, 31), 1, 0),
+            ],
+        };
+        assert_eq!(cur.new_since(&prev), vec![(30, 31)]);
+
+        // The second object unloaded and reloaded at the same address, so it moved to the end.
+        cur.adds = Some(4);
+        cur.subs = Some(1);
+        cur.objects = vec![((0, 1), 1, 0), ((20, 21), 1, 0), ((10, 11), 1, 0)];
+        assert_eq!(cur.new_since(&prev), vec![(10, 11)]);
+    }
+
+    /// Checks an object which is `dlclose`d and reopened (likely at the same address) is primed
+    /// again.
+    ///
+    /// This uses the system zlib, which must be present but not already loaded, so it runs only
+    /// with `--ignored`.
+    #[test]
+    #[ignore = "needs libz.so.1, not already loaded; run with --ignored"]
+    fn reload() {
+        let _guard = LIVE_TEST_LOCK
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let name = CStr::from_bytes_with_nul(b"libz.so.1\0").unwrap();
+        let is_primed = |out: &Output| {
+            out.report()
+                .objects
+                .iter()
+                .any(|o| o.path.to_string_lossy().contains("libz.so"))
+        };
+        assert!(
+            unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_NOLOAD) }.is_null(),
+            "libz.so.1 is already loaded"
+        );
+        let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW) };
+        assert!(!handle.is_null(), "libz.so.1 is unavailable");
+        assert!(is_primed(&crate::prime_new_objects().mlock(true).run()));
+        assert_eq!(unsafe { libc::dlclose(handle) }, 0);
+        assert!(
+            unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_NOLOAD) }.is_null()
+        );
+        let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW) };
+        assert!(!handle.is_null());
+        assert!(is_primed(&crate::prime_new_objects().mlock(true).run()));
+        unsafe { libc::dlclose(handle) };
+    }
+
+    #[test]
+    fn lock_estimate_filtered() {
+        let config = RemapConfig {
+            huge_page_size: None,
+            remap_strategies: Vec::new(),
+            mthp_sizes: Vec::new(),
+            large_page_size: None,
+        };
+        let walk = PlanContext::take();
+        assert!(lock_estimate(&config, &walk, None, |_| Action::BOTH) > 0);
+        assert_eq!(
+            lock_estimate(&config, &walk, None, |_| Action::REMAP_ONLY),
+            0
+        );
+    }
+
     #[test]
     fn test_huge_page_size() {
         assert_eq!(parse_huge_page_size(b"2097152\n").unwrap(), 2097152);
         huge_page_size().unwrap();
     }
+
+    use sim::{Backing, Op, Sim};
+
+    const BASE_MASK: usize = 0xfff;
+    const HUGE: usize = 0x20_0000;
+    const RX: libc::c_int = libc::PROT_READ | libc::PROT_EXEC;
+    const PATH: &[u8] = b"/bin/test\0";
+
+    fn segment(sim: &Sim, addrs: Range<usize>) -> Segment<'_> {
+        Segment {
+            sys: sim,
+            flags: PF_R | PF_X,
+            addrs,
+            path: PATH.as_ptr() as *const libc::c_char,
+        }
+    }
+
+    #[test]
+    fn reservation() {
+        let sim = Sim::new();
+        sim.map_file(HUGE..HUGE + 0x1000, RX);
+        let before = sim.mappings();
+        unsafe {
+            let r = Reservation::new(&sim, HUGE + 0x1000..2 * HUGE).unwrap();
+            assert_eq!(sim.mappings().len(), 2);
+            drop(r);
+            assert_eq!(sim.mappings(), before);
+            assert!(Reservation::new(&sim, HUGE..2 * HUGE).is_none());
+            assert_eq!(sim.mappings(), before);
+        }
+
+        // Older kernels treat MAP_FIXED_NOREPLACE as a hint and map elsewhere on collision.
+        let sim = Sim::new().ignore_fixed_noreplace();
+        sim.map_file(HUGE..HUGE + 0x1000, RX);
+        let before = sim.mappings();
+        unsafe {
+            assert!(Reservation::new(&sim, HUGE..2 * HUGE).is_none());
+            assert_eq!(sim.mappings(), before);
+            assert!(Reservation::new(&sim, HUGE + 0x1000..2 * HUGE).is_some());
+        }
+    }
+
+    /// Checks `mlock` failures are reported per segment, whether locking during the walk or after.
+    #[test]
+    fn mlock_failure() {
+        let sim = Sim::new();
+        let text = HUGE..HUGE + 0x2000;
+        let rodata = HUGE + 0x2000..HUGE + 0x3000;
+        sim.map_file(text.clone(), RX);
+        sim.map_file(rodata.clone(), libc::PROT_READ);
+        let mut phdrs = [unsafe { std::mem::zeroed::<ElfPhdr>() }; 2];
+        for (p, (flags, addrs)) in phdrs
+            .iter_mut()
+            .zip([(PF_R | PF_X, &text), (PF_R, &rodata)])
+        {
+            p.p_type = libc::PT_LOAD;
+            p.p_flags = flags;
+            p.p_vaddr = addrs.start as _;
+            p.p_memsz = addrs.len() as _;
+        }
+        let mut info: libc::dl_phdr_info = unsafe { std::mem::zeroed() };
+        info.dlpi_name = PATH.as_ptr() as *const libc::c_char;
+        info.dlpi_phdr = phdrs.as_ptr();
+        info.dlpi_phnum = 2;
+
+        let config = RemapConfig {
+            huge_page_size: None,
+            remap_strategies: Vec::new(),
+            mthp_sizes: Vec::new(),
+            large_page_size: None,
+        };
+        let options = crate::prime().mlock(true);
+        let mut ctx = Context::new(&sim, &options, &config, None, (1, 2));
+        ctx.walked = 1;
+        sim.fail_nth(Op::Mlock, 1, libc::ENOMEM);
+        unsafe { phdr_cb_inner(&info, &mut ctx) };
+        let mut report = Report {
+            base_page_size: 0x1000,
+            segments: ctx.segments,
+            ..Default::default()
+        };
+        assert_eq!(report.segments[0].mlock, Some(Ok(())));
+        assert_eq!(report.segments[1].mlock, Some(Err(libc::ENOMEM)));
+        let unlocked: Vec<_> = report.unlocked_segments().map(|s| &s.addrs).collect();
+        assert_eq!(unlocked, [&rodata]);
+        assert!(sim.mappings()[0].locked);
+
+        sim.fail_nth(Op::Mlock, 0, libc::EPERM);
+        lock_segments(&sim, LockMode::All, &mut report, &AtomicUsize::new(0));
+        assert!(report
+            .segments
+            .iter()
+            .all(|s| s.mlock == Some(Err(libc::EPERM)) && s.mlock_mode == Some(LockMode::All)));
+        assert_eq!(report.unlocked_segments().count(), 2);
+    }
+
+    /// Checks that `run_objects` takes only the empty-named object at the main executable's load
+    /// bias to be the main executable, not others such as the vDSO.
+    #[test]
+    fn run_objects_main() {
+        const MAIN_BIAS: usize = 0x1000_0000;
+        const VDSO_BIAS: usize = 0x2000_0000;
+        let sim = Sim::new();
+        sim.map_file(MAIN_BIAS + HUGE..MAIN_BIAS + HUGE + 0x1000, RX);
+        sim.map_file(VDSO_BIAS..VDSO_BIAS + 0x1000, RX);
+        let mut phdr: ElfPhdr = unsafe { std::mem::zeroed() };
+        phdr.p_type = libc::PT_LOAD;
+        phdr.p_flags = PF_R | PF_X;
+        phdr.p_memsz = 0x1000;
+        let mut main_phdr = phdr;
+        main_phdr.p_vaddr = HUGE as _;
+        let infos: Vec<libc::dl_phdr_info> = [(VDSO_BIAS, &phdr), (MAIN_BIAS, &main_phdr)]
+            .iter()
+            .map(|&(bias, phdr)| {
+                let mut info: libc::dl_phdr_info = unsafe { std::mem::zeroed() };
+                info.dlpi_addr = bias as _;
+                info.dlpi_name = b"\0".as_ptr() as *const libc::c_char;
+                info.dlpi_phdr = phdr;
+                info.dlpi_phnum = 1;
+                info
+            })
+            .collect();
+
+        let options = crate::prime()
+            .mlock(true)
+            .filter(crate::Filter::main_only());
+        let out = unsafe { run_objects_with(&sim, MAIN_BIAS, options, &infos) };
+        let report = out.report();
+        assert!(!report.objects[0].is_main);
+        assert_eq!(report.objects[0].path, PathBuf::new());
+        assert!(report.objects[1].is_main);
+        assert_eq!(report.segments[0].filter, Some(Action::NEITHER));
+        assert_eq!(report.segments[0].mlock, None);
+        assert_eq!(report.segments[1].filter, Some(Action::BOTH));
+        assert_eq!(report.segments[1].mlock, Some(Ok(())));
+    }
+
+    /// Checks that every failure within `replace` is reported and leaves the address space and
+    /// file descriptors as they were.
+    #[test]
+    fn replace_cleanup() {
+        use RemapStrategy::{AnonThp, HugetlbMemfd, ShmemMemfd};
+        let cases = [
+            (
+                HugetlbMemfd,
+                Op::MemfdCreate,
+                0,
+                HugeError::MemfdCreateFailed(libc::EMFILE),
+            ),
+            (
+                HugetlbMemfd,
+                Op::Ftruncate,
+                0,
+                HugeError::FtruncateFailed(libc::EMFILE),
+            ),
+            (
+                HugetlbMemfd,
+                Op::Mmap,
+                0,
+                HugeError::InitialMmapFailed(libc::EMFILE),
+            ),
+            (
+                HugetlbMemfd,
+                Op::Mmap,
+                1,
+                HugeError::RemapFailed(libc::EMFILE),
+            ),
+            (
+                ShmemMemfd,
+                Op::Mmap,
+                0,
+                HugeError::InitialMmapFailed(libc::EMFILE),
+            ),
+            (
+                ShmemMemfd,
+                Op::Mmap,
+                1,
+                HugeError::InitialMmapFailed(libc::EMFILE),
+            ),
+            (
+                ShmemMemfd,
+                Op::Madvise,
+                0,
+                HugeError::MadviseFailed(libc::EMFILE),
+            ),
+            (
+                ShmemMemfd,
+                Op::Mmap,
+                2,
+                HugeError::RemapFailed(libc::EMFILE),
+            ),
+            (
+                AnonThp,
+                Op::Mmap,
+                0,
+                HugeError::InitialMmapFailed(libc::EMFILE),
+            ),
+            (
+                AnonThp,
+                Op::Mmap,
+                1,
+                HugeError::InitialMmapFailed(libc::EMFILE),
+            ),
+            (
+                AnonThp,
+                Op::Madvise,
+                0,
+                HugeError::MadviseFailed(libc::EMFILE),
+            ),
+            (
+                AnonThp,
+                Op::Mprotect,
+                0,
+                HugeError::MprotectFailed(libc::EMFILE),
+            ),
+            (AnonThp, Op::Mremap, 0, HugeError::RemapFailed(libc::EMFILE)),
+        ];
+        for (strategy, op, n, expected) in cases {
+            let sim = Sim::new();
+            sim.map_file(HUGE..2 * HUGE, RX);
+            let before = sim.mappings();
+            sim.fail_nth(op, n, libc::EMFILE);
+            let path = PATH.as_ptr() as *const libc::c_char;
+            let r = unsafe {
+                replace(
+                    &sim,
+                    strategy,
+                    path,
+                    HUGE..2 * HUGE,
+                    HUGE..2 * HUGE,
+                    PF_R | PF_X,
+                    HUGE - 1,
+                )
+            };
+            assert_eq!(r, Err(expected), "{strategy} {op:?} {n}");
+            assert_eq!(sim.mappings(), before, "{strategy} {op:?} {n}");
+            assert_eq!(sim.open_fds(), 0, "{strategy} {op:?} {n}");
+        }
+    }
+
+    #[test]
+    fn fallback() {
+        let sim = Sim::new();
+        let addrs = HUGE + 0x800..2 * HUGE - 0x800;
+        sim.map_file(HUGE..2 * HUGE, RX);
+        sim.fail_nth(Op::Ftruncate, 0, libc::ENOMEM);
+        let mut report = SegmentReport::for_test(PF_R | PF_X, addrs.clone());
+        let strategies = [RemapStrategy::HugetlbMemfd, RemapStrategy::AnonThp];
+        unsafe {
+            segment(&sim, addrs).remap(BASE_MASK, &[HUGE - 1], None, &strategies, &mut report)
+        };
+        assert_eq!(report.remap, Some(Ok(HUGE..2 * HUGE)));
+        assert_eq!(report.remap_strategy, Some(RemapStrategy::AnonThp));
+        assert_eq!(
+            report.remap_fallbacks.iter().collect::<Vec<_>>(),
+            vec![(
+                RemapStrategy::HugetlbMemfd,
+                HugeError::FtruncateFailed(libc::ENOMEM)
+            )]
+        );
+        let m = sim.mappings();
+        assert_eq!(m.len(), 1);
+        assert_eq!(m[0].range, HUGE..2 * HUGE);
+        assert_eq!(m[0].backing, Backing::Anon);
+        assert_eq!(m[0].prot, RX);
+        assert!(m[0].huge && m[0].named);
+        assert_eq!(sim.open_fds(), 0);
+    }
+
+    /// Checks that a failure with large pages is recorded before falling back to huge pages.
+    #[test]
+    fn large_fallback() {
+        const LARGE: usize = 4 * HUGE;
+        let sim = Sim::new();
+        let addrs = LARGE..2 * LARGE;
+        sim.map_file(addrs.clone(), RX);
+        sim.fail_nth(Op::Ftruncate, 0, libc::ENOMEM);
+        let mut report = SegmentReport::for_test(PF_R | PF_X, addrs.clone());
+        let strategies = [RemapStrategy::HugetlbMemfd];
+        unsafe {
+            segment(&sim, addrs.clone()).remap(
+                BASE_MASK,
+                &[HUGE - 1],
+                Some(LARGE - 1),
+                &strategies,
+                &mut report,
+            )
+        };
+        assert_eq!(report.remap, Some(Ok(addrs.clone())));
+        assert_eq!(report.remap_strategy, Some(RemapStrategy::HugetlbMemfd));
+        assert_eq!(report.large_pages, None);
+        assert_eq!(
+            report.remap_fallbacks.iter().collect::<Vec<_>>(),
+            vec![(
+                RemapStrategy::HugetlbMemfd,
+                HugeError::FtruncateFailed(libc::ENOMEM)
+            )]
+        );
+        assert_eq!(sim.open_fds(), 0);
+    }
+
+    /// Checks remapping of every layout of a segment within four huge pages, with optional
+    /// neighbours directly before and after.
+    #[test]
+    fn partial_coverage() {
+        const HUGE_MASK: usize = 0x3fff;
+        for start in 0..16 {
+            for end in start + 1..=16 {
+                for (before, after) in [(false, false), (true, false), (false, true), (true, true)]
+                {
+                    let sim = Sim::new();
+                    let pages = 0x10000 + start * 0x1000..0x10000 + end * 0x1000;
+                    sim.map_file(pages.clone(), RX);
+                    let mut neighbours = Vec::new();
+                    if before && (pages.start & HUGE_MASK) != 0 {
+                        neighbours.push(pages.start - 0x1000..pages.start);
+                    }
+                    if after && (pages.end & HUGE_MASK) != 0 {
+                        neighbours.push(pages.end..pages.end + 0x1000);
+                    }
+                    for n in &neighbours {
+                        sim.map_file(n.clone(), libc::PROT_READ);
+                    }
+                    let mut report = SegmentReport::for_test(PF_R | PF_X, pages.clone());
+                    unsafe {
+                        segment(&sim, pages.clone()).remap(
+                            BASE_MASK,
+                            &[HUGE_MASK],
+                            None,
+                            &[RemapStrategy::HugetlbMemfd],
+                            &mut report,
+                        )
+                    };
+                    let expected = plan_replacement(&pages, HUGE_MASK, |r| {
+                        neighbours
+                            .iter()
+                            .all(|n| n.end <= r.start || r.end <= n.start)
+                    });
+                    let mappings = sim.mappings();
+                    let ctx = format!("{pages:x?} {neighbours:x?} {mappings:x?}");
+                    let Some(expected) = expected else {
+                        assert_eq!(report.remap, Some(Err(HugeError::Conflict)), "{ctx}");
+                        assert_eq!(mappings.len(), 1 + neighbours.len(), "{ctx}");
+                        continue;
+                    };
+                    assert_eq!(report.remap, Some(Ok(expected.map.clone())), "{ctx}");
+                    for m in &mappings {
+                        // No reservation is left behind, and neighbours are untouched.
+                        assert_ne!(m.prot, libc::PROT_NONE, "{ctx}");
+                        let remapped = matches!(m.backing, Backing::Memfd { hugetlb: true, .. });
+                        assert_eq!(remapped, m.range == expected.map, "{ctx}");
+                    }
+                    let covered: usize = mappings
+                        .iter()
+                        .filter(|m| m.backing != Backing::File || !neighbours.contains(&m.range))
+                        .map(|m| m.range.len())
+                        .sum();
+                    assert_eq!(
+                        covered,
+                        pages.len()
+                            + expected
+                                .reserve
+                                .iter()
+                                .flatten()
+                                .map(Range::len)
+                                .sum::<usize>(),
+                        "{ctx}"
+                    );
+                    assert_eq!(sim.open_fds(), 0, "{ctx}");
+                }
+            }
+        }
+    }
 }
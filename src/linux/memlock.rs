@@ -0,0 +1,256 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The `RLIMIT_MEMLOCK` preflight and accounting; see [`Report::memlock`].
+
+use super::smaps;
+use crate::report::{Memlock, Report};
+use crate::LockMode;
+
+/// `CAP_IPC_LOCK` from `<linux/capability.h>`.
+const CAP_IPC_LOCK: u32 = 14;
+
+/// `_LINUX_CAPABILITY_VERSION_3`, which uses two [`CapData`] entries.
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// `struct __user_cap_header_struct`.
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+/// `struct __user_cap_data_struct`.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Returns true iff this thread has `CAP_IPC_LOCK` in its effective set.
+fn has_cap_ipc_lock() -> bool {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    let ret = unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) };
+    ret == 0 && (data[0].effective & (1 << CAP_IPC_LOCK)) != 0
+}
+
+#[allow(clippy::unnecessary_cast)] // `rlim_t` is narrower on some 32-bit targets.
+fn to_limit(v: libc::rlim_t) -> Option<u64> {
+    match v {
+        libc::RLIM_INFINITY => None,
+        v => Some(v as u64),
+    }
+}
+
+/// Returns true iff locking `m.needed` bytes exceeds the soft limit, and raising it to the hard
+/// limit would allow more.
+fn should_raise(m: &Memlock) -> bool {
+    match (m.cap_ipc_lock, m.soft_limit) {
+        (false, Some(soft)) => {
+            m.required_limit() as u64 > soft && m.hard_limit.map_or(true, |h| h > soft)
+        }
+        _ => false,
+    }
+}
+
+/// Returns the bytes `mlockall` would newly lock: the whole address space, less what's already
+/// locked. This includes the heap, stacks, and anonymous mappings as well as the segments.
+///
+/// The kernel likewise checks `VmSize` against the limit for `MCL_CURRENT`.
+fn process_wide_needed(
+    already_locked: usize,
+    log: &mut Vec<(log::Level, String)>,
+) -> Option<usize> {
+    match smaps::read_vm_size() {
+        Ok(v) => Some(v.saturating_sub(already_locked)),
+        Err(e) => {
+            log.push((
+                log::Level::Warn,
+                format!(
+                    "Unable to read VmSize: {e}. Can't estimate what mlockall needs beyond the \
+                     segments."
+                ),
+            ));
+            None
+        }
+    }
+}
+
+/// Checks whether locking `needed` bytes fits within `RLIMIT_MEMLOCK`, raising the soft limit to
+/// the hard limit if not. For process-wide `mode`s, `needed` is replaced with
+/// [`process_wide_needed`].
+///
+/// The limit is raised even if the hard limit doesn't suffice either, so as much as possible is
+/// locked.
+pub(crate) fn preflight(
+    mode: LockMode,
+    needed: usize,
+    log: &mut Vec<(log::Level, String)>,
+) -> Memlock {
+    let mut rlim = libc::rlimit {
+        rlim_cur: libc::RLIM_INFINITY,
+        rlim_max: libc::RLIM_INFINITY,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut rlim) } != 0 {
+        log.push((
+            log::Level::Warn,
+            format!(
+                "Unable to get RLIMIT_MEMLOCK: {}",
+                std::io::Error::last_os_error()
+            ),
+        ));
+    }
+    let already_locked = match smaps::read_vm_lck() {
+        Ok(v) => v,
+        Err(e) => {
+            log.push((log::Level::Warn, format!("Unable to read VmLck: {e}")));
+            0
+        }
+    };
+    let needed = match mode.is_process_wide() {
+        true => process_wide_needed(already_locked, log).unwrap_or(needed),
+        false => needed,
+    };
+    let mut m = Memlock {
+        needed,
+        already_locked,
+        soft_limit: to_limit(rlim.rlim_cur),
+        hard_limit: to_limit(rlim.rlim_max),
+        cap_ipc_lock: has_cap_ipc_lock(),
+        ..Default::default()
+    };
+    if let (true, Some(soft)) = (should_raise(&m), m.soft_limit) {
+        let raised = libc::rlimit {
+            rlim_cur: rlim.rlim_max,
+            rlim_max: rlim.rlim_max,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &raised) } == 0 {
+            m.raised = true;
+            log.push((
+                log::Level::Info,
+                format!(
+                    "Raised RLIMIT_MEMLOCK soft limit from {soft} to {} to lock {} bytes.",
+                    m.hard_limit
+                        .map_or_else(|| "unlimited".to_owned(), |h| h.to_string()),
+                    m.required_limit(),
+                ),
+            ));
+        } else {
+            log.push((
+                log::Level::Warn,
+                format!(
+                    "Unable to raise RLIMIT_MEMLOCK: {}",
+                    std::io::Error::last_os_error()
+                ),
+            ));
+        }
+    }
+    m.update_shortfall();
+    m
+}
+
+/// Updates [`Report::memlock`] with the exact bytes needed now that locking is done (or, for
+/// `mlockall`, the current [`process_wide_needed`]), logging any shortfall.
+pub(crate) fn account(report: &mut Report, log: &mut Vec<(log::Level, String)>) {
+    let base_page_size = report.base_page_size;
+    let process_wide = report
+        .segments
+        .iter()
+        .find_map(|s| s.mlock_mode)
+        .is_some_and(LockMode::is_process_wide);
+    let mut needed = report
+        .segments
+        .iter()
+        .filter(|s| s.mlock.is_some())
+        .map(|s| s.lock_range(base_page_size).len())
+        .sum();
+    let (unlocked, unlocked_bytes) = report.unlocked_segments().fold((0, 0), |(n, bytes), s| {
+        (n + 1, bytes + s.lock_range(base_page_size).len())
+    });
+    let Some(m) = report.memlock.as_mut() else {
+        return;
+    };
+    if process_wide {
+        needed = process_wide_needed(m.already_locked, log).unwrap_or(needed);
+    }
+    m.needed = needed;
+    m.update_shortfall();
+    if m.shortfall > 0 {
+        log.push((
+            log::Level::Warn,
+            format!(
+                "RLIMIT_MEMLOCK is {} bytes short; {unlocked} segments ({unlocked_bytes} bytes) \
+                 were left unlocked. Set LimitMEMLOCK={} in the systemd unit (or ulimit -l {}), \
+                 or grant CAP_IPC_LOCK.",
+                m.shortfall,
+                m.required_limit(),
+                m.required_limit().div_ceil(1024),
+            ),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linux::{plan_segments, RemapConfig};
+    use crate::report::{PF_R, PF_X};
+    use crate::Action;
+
+    #[test]
+    fn shortfall() {
+        let mut m = Memlock {
+            needed: 3 << 20,
+            already_locked: 1 << 20,
+            soft_limit: Some(1 << 20),
+            hard_limit: Some(2 << 20),
+            ..Default::default()
+        };
+        m.update_shortfall();
+        assert_eq!(m.shortfall, 3 << 20);
+        m.raised = true;
+        m.update_shortfall();
+        assert_eq!(m.shortfall, 2 << 20);
+        m.cap_ipc_lock = true;
+        m.update_shortfall();
+        assert_eq!(m.shortfall, 0);
+    }
+
+    #[test]
+    fn huge_page_padding() {
+        // 8 KiB of text straddling a huge page boundary, which remapping locks as 4 MiB if the
+        // surrounding space is free.
+        let config = RemapConfig {
+            huge_page_size: Some(2 << 20),
+            remap_strategies: vec![crate::RemapStrategy::HugetlbMemfd],
+            mthp_sizes: Vec::new(),
+            large_page_size: None,
+        };
+        let huge_page_sizes = config.huge_page_sizes();
+        let planner = config.planner(&huge_page_sizes, true);
+        let segments = [(0, PF_R | PF_X, (2 << 20) - 0x1000..(2 << 20) + 0x1000)];
+        let needed = |occupied| -> usize {
+            plan_segments(&planner, segments.clone(), |_| Action::BOTH, occupied)
+                .iter()
+                .filter_map(|s| s.lock.as_ref())
+                .map(|l| l.len())
+                .sum()
+        };
+        let mut m = Memlock {
+            needed: needed(None),
+            soft_limit: Some(1 << 20),
+            hard_limit: Some(8 << 20),
+            ..Default::default()
+        };
+        assert!(!should_raise(&m));
+        m.needed = needed(Some(Vec::new()));
+        assert_eq!(m.needed, 4 << 20);
+        assert!(should_raise(&m));
+    }
+}
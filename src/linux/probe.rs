@@ -0,0 +1,188 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Probing which [`RemapStrategy`]s the running kernel can support, before touching memory.
+
+use crate::RemapStrategy;
+use std::ffi::CStr;
+
+const THP_DIR: &str = "/sys/kernel/mm/transparent_hugepage";
+const THP_ENABLED_PATH: &str = "/sys/kernel/mm/transparent_hugepage/enabled";
+const SHMEM_ENABLED_PATH: &str = "/sys/kernel/mm/transparent_hugepage/shmem_enabled";
+
+/// What the kernel reports about its huge page support.
+#[derive(Debug, Default)]
+pub(crate) struct Probe {
+    /// The free and overcommit hugetlb page counts for the huge page size, if readable.
+    hugetlb: Option<(u64, u64)>,
+
+    /// The selected mode of `/sys/kernel/mm/transparent_hugepage/enabled`, if readable.
+    thp_enabled: Option<String>,
+
+    /// The selected mode of `/sys/kernel/mm/transparent_hugepage/shmem_enabled`, if readable.
+    shmem_enabled: Option<String>,
+
+    /// The kernel's `(major, minor)` version, if parseable.
+    kernel_version: Option<(u32, u32)>,
+
+    /// The multi-size THP (mTHP) sizes and the selected mode of each size's `enabled` file.
+    ///
+    /// Empty on kernels before 6.8, which lack per-size controls.
+    mthp: Vec<(usize, String)>,
+}
+
+/// Reads a sysfs file, returning `None` if it doesn't exist or can't be read.
+fn read_sysfs(path: &str) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+/// Returns the selected mode in a sysfs mode list such as `always [madvise] never`.
+fn parse_mode(data: &str) -> Option<String> {
+    let start = data.find('[')? + 1;
+    let end = start + data[start..].find(']')?;
+    Some(data[start..end].to_owned())
+}
+
+/// Parses the major and minor version from a kernel release such as `6.1.0-13-amd64`.
+fn parse_kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Parses the page size from an mTHP directory name such as `hugepages-64kB`.
+fn parse_mthp_dir(name: &str) -> Option<usize> {
+    let kb: usize = name
+        .strip_prefix("hugepages-")?
+        .strip_suffix("kB")?
+        .parse()
+        .ok()?;
+    kb.checked_mul(1024)
+}
+
+/// Reads each mTHP size's `enabled` mode.
+fn read_mthp() -> Vec<(usize, String)> {
+    let Ok(dir) = std::fs::read_dir(THP_DIR) else {
+        return Vec::new();
+    };
+    dir.filter_map(|e| {
+        let e = e.ok()?;
+        let size = parse_mthp_dir(e.file_name().to_str()?)?;
+        let mode = parse_mode(&read_sysfs(e.path().join("enabled").to_str()?)?)?;
+        Some((size, mode))
+    })
+    .collect()
+}
+
+fn kernel_release() -> Option<String> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } == -1 {
+        return None;
+    }
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) };
+    Some(release.to_string_lossy().into_owned())
+}
+
+/// Returns the available and overcommit page counts of the hugetlb pool of the given page size,
+/// or `None` if there's no such pool.
+///
+/// Free pages already reserved (`resv_hugepages`) are promised to other mappings, so they don't
+/// count as available.
+pub(crate) fn hugetlb_pool(page_size: usize) -> Option<(u64, u64)> {
+    let dir = format!("/sys/kernel/mm/hugepages/hugepages-{}kB", page_size >> 10);
+    let read_count =
+        |name: &str| -> Option<u64> { read_sysfs(&format!("{dir}/{name}"))?.trim().parse().ok() };
+    let free = read_count("free_hugepages")?;
+    let reserved = read_count("resv_hugepages").unwrap_or(0);
+    Some((
+        free.saturating_sub(reserved),
+        read_count("nr_overcommit_hugepages").unwrap_or(0),
+    ))
+}
+
+impl Probe {
+    /// Probes the running kernel for the given huge page size.
+    pub(crate) fn new(huge_page_size: usize) -> Self {
+        Probe {
+            hugetlb: hugetlb_pool(huge_page_size),
+            thp_enabled: read_sysfs(THP_ENABLED_PATH).and_then(|d| parse_mode(&d)),
+            shmem_enabled: read_sysfs(SHMEM_ENABLED_PATH).and_then(|d| parse_mode(&d)),
+            kernel_version: kernel_release().and_then(|r| parse_kernel_version(&r)),
+            mthp: read_mthp(),
+        }
+    }
+
+    /// Returns the enabled anonymous mTHP sizes smaller than `huge_page_size`, largest first.
+    pub(crate) fn anon_mthp_sizes(&self, huge_page_size: usize) -> Vec<usize> {
+        let inherited = !matches!(self.thp_enabled.as_deref(), None | Some("never"));
+        let mut sizes: Vec<usize> = self
+            .mthp
+            .iter()
+            .filter(|(size, mode)| {
+                *size < huge_page_size
+                    && match mode.as_str() {
+                        "always" | "madvise" => true,
+                        "inherit" => inherited,
+                        _ => false,
+                    }
+            })
+            .map(|&(size, _)| size)
+            .collect();
+        sizes.sort_unstable_by(|a, b| b.cmp(a));
+        sizes
+    }
+
+    /// Returns `Err` with a reason if `strategy` definitely won't work.
+    ///
+    /// `Ok` doesn't guarantee success; e.g. `MADV_COLLAPSE` on file-backed text also requires
+    /// `CONFIG_READ_ONLY_THP_FOR_FS`, which can't be probed.
+    pub(crate) fn check(&self, strategy: RemapStrategy) -> Result<(), String> {
+        match strategy {
+            RemapStrategy::HugetlbMemfd => match self.hugetlb {
+                None => Err("no hugetlb pool for this huge page size".to_owned()),
+                Some((0, 0)) => Err("no unreserved hugetlb pages".to_owned()),
+                Some(_) => Ok(()),
+            },
+            RemapStrategy::AnonThp => match self.thp_enabled.as_deref() {
+                None => Err(format!("{THP_ENABLED_PATH} unreadable")),
+                Some("never") => Err("transparent huge pages disabled".to_owned()),
+                Some(_) => Ok(()),
+            },
+            RemapStrategy::ShmemMemfd => match self.shmem_enabled.as_deref() {
+                None => Err(format!("{SHMEM_ENABLED_PATH} unreadable")),
+                Some(m @ ("never" | "deny")) => Err(format!("shmem huge pages set to {m}")),
+                Some(_) => Ok(()),
+            },
+            RemapStrategy::Collapse => match self.kernel_version {
+                Some(v) if v >= (6, 1) => Ok(()),
+                Some((major, minor)) => Err(format!("kernel {major}.{minor} predates 6.1")),
+                None => Err("unable to determine kernel version".to_owned()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_mode("always [madvise] never\n").as_deref(),
+            Some("madvise")
+        );
+        assert_eq!(
+            parse_mode("always within_size advise [never] deny force\n").as_deref(),
+            Some("never")
+        );
+        assert_eq!(parse_mode("garbage"), None);
+        assert_eq!(parse_kernel_version("6.1.0-13-amd64"), Some((6, 1)));
+        assert_eq!(parse_kernel_version("5.10.226"), Some((5, 10)));
+        assert_eq!(parse_kernel_version("garbage"), None);
+        assert_eq!(parse_mthp_dir("hugepages-64kB"), Some(65536));
+        assert_eq!(parse_mthp_dir("hugepages-2048kB"), Some(2097152));
+        assert_eq!(parse_mthp_dir("khugepaged"), None);
+    }
+}
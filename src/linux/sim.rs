@@ -0,0 +1,340 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! A simulated kernel implementing [`Sys`], for testing the remap engine without touching the
+//! real address space or needing a hugetlb pool.
+
+use super::sys::Sys;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Where a simulated mapping's contents come from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Backing {
+    /// The original file mapping, as set up by [`Sim::map_file`].
+    File,
+    Anon,
+    Memfd {
+        fd: libc::c_int,
+        hugetlb: bool,
+    },
+}
+
+/// A simulated mapping.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Mapping {
+    pub(crate) range: Range<usize>,
+    pub(crate) prot: libc::c_int,
+    pub(crate) backing: Backing,
+
+    /// True iff advised with `MADV_HUGEPAGE` or `MADV_COLLAPSE`.
+    pub(crate) huge: bool,
+    pub(crate) locked: bool,
+    pub(crate) named: bool,
+}
+
+/// A simulated operation, for failure injection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Op {
+    Mmap,
+    Mremap,
+    Mprotect,
+    Madvise,
+    Mlock,
+    MemfdCreate,
+    Ftruncate,
+}
+
+#[derive(Default)]
+struct State {
+    mappings: BTreeMap<usize, Mapping>,
+
+    /// Open file descriptors and their `hugetlb` flag.
+    fds: BTreeMap<libc::c_int, bool>,
+    next_fd: libc::c_int,
+    next_addr: usize,
+
+    /// Injected failures: the operation, the number of further calls to let succeed first, and
+    /// the `errno` to fail with.
+    failures: Vec<(Op, usize, i32)>,
+
+    /// Simulates kernels before 4.17, which treat `MAP_FIXED_NOREPLACE` as a hint.
+    ignore_fixed_noreplace: bool,
+}
+
+/// A simulated kernel.
+pub(crate) struct Sim(RefCell<State>);
+
+impl Sim {
+    pub(crate) fn new() -> Self {
+        Sim(RefCell::new(State {
+            next_fd: 3,
+            next_addr: 0x7000_0000_0000,
+            ..Default::default()
+        }))
+    }
+
+    /// Simulates a kernel which doesn't recognize `MAP_FIXED_NOREPLACE`.
+    pub(crate) fn ignore_fixed_noreplace(self) -> Self {
+        self.0.borrow_mut().ignore_fixed_noreplace = true;
+        self
+    }
+
+    /// Maps `range` as if loaded from a file.
+    pub(crate) fn map_file(&self, range: Range<usize>, prot: libc::c_int) {
+        self.0.borrow_mut().insert(range, prot, Backing::File);
+    }
+
+    /// Makes the `n`th subsequent call (counting from 0) to `op` fail with `errno`.
+    pub(crate) fn fail_nth(&self, op: Op, n: usize, errno: i32) {
+        self.0.borrow_mut().failures.push((op, n, errno));
+    }
+
+    /// Returns all mappings, in address order. Adjacent mappings aren't merged.
+    pub(crate) fn mappings(&self) -> Vec<Mapping> {
+        self.0.borrow().mappings.values().cloned().collect()
+    }
+
+    /// Returns the number of open file descriptors.
+    pub(crate) fn open_fds(&self) -> usize {
+        self.0.borrow().fds.len()
+    }
+
+    fn check(&self, op: Op) -> Result<(), i32> {
+        let mut state = self.0.borrow_mut();
+        let mut result = Ok(());
+        state.failures.retain_mut(|(o, n, errno)| {
+            if *o != op {
+                return true;
+            }
+            if *n > 0 {
+                *n -= 1;
+                return true;
+            }
+            result = Err(*errno);
+            false
+        });
+        result
+    }
+}
+
+impl State {
+    /// Splits the mapping containing `addr`, if any, so that a mapping starts at `addr`.
+    fn split_at(&mut self, addr: usize) {
+        let Some((_, m)) = self.mappings.range_mut(..addr).next_back() else {
+            return;
+        };
+        if m.range.end <= addr {
+            return;
+        }
+        let mut tail = m.clone();
+        m.range.end = addr;
+        tail.range.start = addr;
+        self.mappings.insert(addr, tail);
+    }
+
+    /// Splits mappings at the ends of `range`, returning the start addresses of those within.
+    fn isolate(&mut self, range: &Range<usize>) -> Vec<usize> {
+        self.split_at(range.start);
+        self.split_at(range.end);
+        self.mappings
+            .range(range.clone())
+            .map(|(&a, _)| a)
+            .collect()
+    }
+
+    fn overlaps(&self, range: &Range<usize>) -> bool {
+        self.mappings
+            .values()
+            .any(|m| m.range.start < range.end && range.start < m.range.end)
+    }
+
+    /// Returns true iff `range` is entirely mapped.
+    fn is_mapped(&self, range: &Range<usize>) -> bool {
+        let mut next = range.start;
+        for m in self.mappings.values() {
+            if m.range.end <= next || m.range.start >= range.end {
+                continue;
+            }
+            if m.range.start > next {
+                return false;
+            }
+            next = m.range.end;
+        }
+        next >= range.end
+    }
+
+    fn unmap(&mut self, range: &Range<usize>) {
+        for a in self.isolate(range) {
+            self.mappings.remove(&a);
+        }
+    }
+
+    fn insert(&mut self, range: Range<usize>, prot: libc::c_int, backing: Backing) {
+        self.unmap(&range);
+        self.mappings.insert(
+            range.start,
+            Mapping {
+                range,
+                prot,
+                backing,
+                huge: false,
+                locked: false,
+                named: false,
+            },
+        );
+    }
+
+    /// Picks an unused address for a mapping of `len` bytes.
+    fn pick(&mut self, len: usize) -> usize {
+        let addr = self.next_addr;
+        self.next_addr += (len + 0xfff) & !0xfff;
+        addr
+    }
+
+    /// Applies `f` to every mapping within `range`, failing with `ENOMEM` if any of it is
+    /// unmapped.
+    fn modify(&mut self, range: &Range<usize>, f: impl Fn(&mut Mapping)) -> Result<(), i32> {
+        if !self.is_mapped(range) {
+            return Err(libc::ENOMEM);
+        }
+        for a in self.isolate(range) {
+            f(self.mappings.get_mut(&a).expect("isolated mapping exists"));
+        }
+        Ok(())
+    }
+}
+
+impl Sys for Sim {
+    unsafe fn mmap(
+        &self,
+        addr: usize,
+        len: usize,
+        prot: libc::c_int,
+        flags: libc::c_int,
+        fd: libc::c_int,
+    ) -> Result<usize, i32> {
+        self.check(Op::Mmap)?;
+        let mut state = self.0.borrow_mut();
+        let backing = match fd {
+            -1 => Backing::Anon,
+            fd => Backing::Memfd {
+                fd,
+                hugetlb: *state.fds.get(&fd).ok_or(libc::EBADF)?,
+            },
+        };
+        let range = addr..addr + len;
+        let addr = if (flags & libc::MAP_FIXED) != 0 {
+            addr
+        } else if (flags & libc::MAP_FIXED_NOREPLACE) != 0 && !state.ignore_fixed_noreplace {
+            if state.overlaps(&range) {
+                return Err(libc::EEXIST);
+            }
+            addr
+        } else if addr != 0 && !state.overlaps(&range) {
+            addr
+        } else {
+            state.pick(len)
+        };
+        state.insert(addr..addr + len, prot, backing);
+        Ok(addr)
+    }
+
+    unsafe fn munmap(&self, range: Range<usize>) {
+        self.0.borrow_mut().unmap(&range);
+    }
+
+    unsafe fn mremap_fixed(&self, old: usize, len: usize, new: usize) -> Result<(), i32> {
+        self.check(Op::Mremap)?;
+        let mut state = self.0.borrow_mut();
+        let old = old..old + len;
+        if !state.is_mapped(&old) {
+            return Err(libc::EFAULT);
+        }
+        let moved: Vec<Mapping> = state
+            .isolate(&old)
+            .into_iter()
+            .map(|a| state.mappings.remove(&a).expect("isolated mapping exists"))
+            .collect();
+        state.unmap(&(new..new + len));
+        for mut m in moved {
+            m.range = m.range.start - old.start + new..m.range.end - old.start + new;
+            state.mappings.insert(m.range.start, m);
+        }
+        Ok(())
+    }
+
+    unsafe fn mprotect(&self, range: Range<usize>, prot: libc::c_int) -> Result<(), i32> {
+        self.check(Op::Mprotect)?;
+        self.0.borrow_mut().modify(&range, |m| m.prot = prot)
+    }
+
+    unsafe fn madvise(&self, range: Range<usize>, advice: libc::c_int) -> Result<(), i32> {
+        self.check(Op::Madvise)?;
+        self.0
+            .borrow_mut()
+            .modify(&range, |m| m.huge |= advice != libc::MADV_NOHUGEPAGE)
+    }
+
+    unsafe fn mlock(&self, range: Range<usize>, _on_fault: bool) -> Result<(), i32> {
+        self.check(Op::Mlock)?;
+        self.0.borrow_mut().modify(&range, |m| m.locked = true)
+    }
+
+    unsafe fn mlockall(&self, _flags: libc::c_int) -> Result<(), i32> {
+        self.check(Op::Mlock)?;
+        for m in self.0.borrow_mut().mappings.values_mut() {
+            m.locked = true;
+        }
+        Ok(())
+    }
+
+    unsafe fn copy(&self, dst: usize, src: usize, len: usize) {
+        let state = self.0.borrow();
+        assert!(
+            state.is_mapped(&(src..src + len)),
+            "copy from unmapped {:#x}+{:#x}",
+            src,
+            len
+        );
+        assert!(
+            state.is_mapped(&(dst..dst + len)),
+            "copy to unmapped {:#x}+{:#x}",
+            dst,
+            len
+        );
+    }
+
+    unsafe fn name_anon(&self, range: Range<usize>, _name: *const u8) {
+        let _ = self.0.borrow_mut().modify(&range, |m| {
+            m.named |= m.backing == Backing::Anon;
+        });
+    }
+
+    unsafe fn memfd_create(
+        &self,
+        _name: *const libc::c_char,
+        flags: libc::c_uint,
+    ) -> Result<libc::c_int, i32> {
+        self.check(Op::MemfdCreate)?;
+        let mut state = self.0.borrow_mut();
+        let fd = state.next_fd;
+        state.next_fd += 1;
+        state.fds.insert(fd, (flags & libc::MFD_HUGETLB) != 0);
+        Ok(fd)
+    }
+
+    unsafe fn ftruncate(&self, fd: libc::c_int, _len: usize) -> Result<(), i32> {
+        self.check(Op::Ftruncate)?;
+        match self.0.borrow().fds.contains_key(&fd) {
+            true => Ok(()),
+            false => Err(libc::EBADF),
+        }
+    }
+
+    unsafe fn close(&self, fd: libc::c_int) {
+        let closed = self.0.borrow_mut().fds.remove(&fd);
+        assert!(closed.is_some(), "closed fd {} which isn't open", fd);
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Verification of priming via `/proc/self/smaps` and `/proc/self/status`, and the memory map
+//! snapshot used by planning.
+
+use crate::report::{Coverage, Report};
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+
+const MAPS_PATH: &str = "/proc/self/maps";
+const SMAPS_PATH: &str = "/proc/self/smaps";
+const STATUS_PATH: &str = "/proc/self/status";
+
+/// A single mapping as described by `/proc/self/smaps`.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Vma {
+    addrs: Range<usize>,
+    kernel_page_size: usize,
+    private_hugetlb: usize,
+    shared_hugetlb: usize,
+    anon_huge_pages: usize,
+    shmem_pmd_mapped: usize,
+    file_pmd_mapped: usize,
+    locked: usize,
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Parses a mapping header line's address range, e.g. `55fbe38fa000-55fbe38fc000 r--p ...`.
+fn parse_header(line: &str) -> Option<Range<usize>> {
+    let range = line.split_ascii_whitespace().next()?;
+    let (start, end) = range.split_once('-')?;
+    Some(usize::from_str_radix(start, 16).ok()?..usize::from_str_radix(end, 16).ok()?)
+}
+
+/// Returns the address ranges of all current mappings, from `/proc/self/maps`.
+pub(crate) fn read_maps() -> Result<Vec<Range<usize>>, Error> {
+    let maps = std::fs::read_to_string(MAPS_PATH)?;
+    maps.lines()
+        .map(|l| {
+            parse_header(l)
+                .ok_or_else(|| invalid(format!("unable to parse {MAPS_PATH} line {l:?}")))
+        })
+        .collect()
+}
+
+/// Parses a `<n> kB` value into bytes.
+fn parse_kb(path: &str, key: &str, value: &str) -> Result<usize, Error> {
+    let n = value
+        .trim()
+        .strip_suffix(" kB")
+        .and_then(|n| n.trim().parse::<usize>().ok())
+        .ok_or_else(|| invalid(format!("unable to parse {path} {key} value {value:?}")))?;
+    Ok(n << 10)
+}
+
+fn parse_smaps(data: &str) -> Result<Vec<Vma>, Error> {
+    let mut vmas: Vec<Vma> = Vec::new();
+    for line in data.lines() {
+        if let Some(addrs) = parse_header(line) {
+            vmas.push(Vma {
+                addrs,
+                ..Default::default()
+            });
+            continue;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let Some(vma) = vmas.last_mut() else {
+            return Err(invalid(format!(
+                "{SMAPS_PATH} has field {key:?} before any mapping"
+            )));
+        };
+        let field = match key {
+            "KernelPageSize" => &mut vma.kernel_page_size,
+            "Private_Hugetlb" => &mut vma.private_hugetlb,
+            "Shared_Hugetlb" => &mut vma.shared_hugetlb,
+            "AnonHugePages" => &mut vma.anon_huge_pages,
+            "ShmemPmdMapped" => &mut vma.shmem_pmd_mapped,
+            "FilePmdMapped" => &mut vma.file_pmd_mapped,
+            "Locked" => &mut vma.locked,
+            _ => continue,
+        };
+        *field = parse_kb(SMAPS_PATH, key, value)?;
+    }
+    Ok(vmas)
+}
+
+/// Parses the `key` value (e.g. `VmLck`) from `/proc/self/status`, in bytes.
+fn parse_status(data: &str, key: &str) -> Result<usize, Error> {
+    for line in data.lines() {
+        if let Some(value) = line.strip_prefix(key).and_then(|l| l.strip_prefix(':')) {
+            return parse_kb(STATUS_PATH, key, value);
+        }
+    }
+    Err(invalid(format!("{STATUS_PATH} has no {key}")))
+}
+
+/// Returns the process's total locked memory in bytes, from `/proc/self/status`.
+pub(crate) fn read_vm_lck() -> Result<usize, Error> {
+    std::fs::read_to_string(STATUS_PATH).and_then(|status| parse_status(&status, "VmLck"))
+}
+
+/// Returns the process's total mapped memory (`VmSize`) in bytes, from `/proc/self/status`.
+pub(crate) fn read_vm_size() -> Result<usize, Error> {
+    std::fs::read_to_string(STATUS_PATH).and_then(|status| parse_status(&status, "VmSize"))
+}
+
+/// Sums coverage of all mappings overlapping `addrs`.
+///
+/// Each overlapping mapping is counted in full, even if it extends outside `addrs`.
+fn coverage(vmas: &[Vma], addrs: &Range<usize>) -> Coverage {
+    let mut c = Coverage::default();
+    for vma in vmas
+        .iter()
+        .filter(|v| v.addrs.start < addrs.end && addrs.start < v.addrs.end)
+    {
+        if !c.kernel_page_sizes.contains(&vma.kernel_page_size) {
+            c.kernel_page_sizes.push(vma.kernel_page_size);
+        }
+        c.private_hugetlb += vma.private_hugetlb;
+        c.shared_hugetlb += vma.shared_hugetlb;
+        c.anon_huge_pages += vma.anon_huge_pages;
+        c.shmem_pmd_mapped += vma.shmem_pmd_mapped;
+        c.file_pmd_mapped += vma.file_pmd_mapped;
+        c.locked += vma.locked;
+    }
+    c.kernel_page_sizes.sort_unstable();
+    c
+}
+
+/// Fills in [`crate::SegmentReport::coverage`] for every remapped or locked segment, and
+/// [`Report::vm_lck`].
+pub(crate) fn verify(report: &mut Report, log: &mut Vec<(log::Level, String)>) {
+    match std::fs::read_to_string(SMAPS_PATH).and_then(|smaps| parse_smaps(&smaps)) {
+        Ok(vmas) => {
+            let base_page_size = report.base_page_size;
+            for seg in &mut report.segments {
+                if seg.remapped().is_none() && !seg.locked() {
+                    continue;
+                }
+                let addrs = seg.lock_range(base_page_size);
+                seg.coverage = Some(coverage(&vmas, &addrs));
+            }
+        }
+        Err(e) => log.push((
+            log::Level::Warn,
+            format!("Unable to verify via {SMAPS_PATH}: {e}"),
+        )),
+    }
+    match read_vm_lck() {
+        Ok(v) => report.vm_lck = Some(v),
+        Err(e) => log.push((log::Level::Warn, format!("Unable to read VmLck: {e}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMAPS: &str = "\
+5646c5400000-5646c5600000 r-xp 00000000 00:01 25220867                   /memfd:/bin/foo (deleted)
+Size:               2048 kB
+KernelPageSize:     2048 kB
+MMUPageSize:        2048 kB
+Rss:                   0 kB
+AnonHugePages:         0 kB
+ShmemPmdMapped:        0 kB
+FilePmdMapped:         0 kB
+Shared_Hugetlb:        0 kB
+Private_Hugetlb:    2048 kB
+Locked:             2048 kB
+THPeligible:           0
+VmFlags: rd ex mr mw me ht
+5646c5600000-5646c5601000 r--p 00200000 103:03 69612122                   /bin/foo
+Size:                  4 kB
+KernelPageSize:        4 kB
+MMUPageSize:           4 kB
+AnonHugePages:         0 kB
+ShmemPmdMapped:        0 kB
+FilePmdMapped:         0 kB
+Shared_Hugetlb:        0 kB
+Private_Hugetlb:       0 kB
+Locked:                4 kB
+VmFlags: rd mr mw me
+";
+
+    #[test]
+    fn parse() {
+        let vmas = parse_smaps(SMAPS).unwrap();
+        assert_eq!(vmas.len(), 2);
+        assert_eq!(
+            vmas[0],
+            Vma {
+                addrs: 0x5646c5400000..0x5646c5600000,
+                kernel_page_size: 2 << 20,
+                private_hugetlb: 2 << 20,
+                locked: 2 << 20,
+                ..Default::default()
+            }
+        );
+        let c = coverage(&vmas, &(0x5646c55ff000..0x5646c5601000));
+        assert_eq!(c.kernel_page_sizes, vec![4 << 10, 2 << 20]);
+        assert_eq!(c.huge_bytes(), 2 << 20);
+        assert_eq!(c.locked, (2 << 20) + (4 << 10));
+        let status = "Name:\tfoo\nVmSize:\t   10240 kB\nVmLck:\t    2052 kB\n";
+        assert_eq!(parse_status(status, "VmLck").unwrap(), 2052 << 10);
+        assert_eq!(parse_status(status, "VmSize").unwrap(), 10240 << 10);
+    }
+}
@@ -0,0 +1,457 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Stop-the-world mode: parks every other thread in a signal handler while remapping.
+//!
+//! Parked threads may hold any lock, including the allocator's, so nothing between
+//! [`Stopper::stop`] and [`Stopper::resume`] may allocate. Threads are enumerated via raw
+//! `getdents64` into a stack buffer, and their ids are recorded in preallocated space.
+
+use crate::report::Skipped;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// The used prefix of glibc's `struct link_map`, from `<link.h>`.
+#[cfg(target_env = "gnu")]
+#[repr(C)]
+struct LinkMap {
+    l_addr: usize,
+    l_name: *const libc::c_char,
+    _l_ld: *const libc::c_void,
+    l_next: *const LinkMap,
+}
+
+/// The used prefix of glibc's `struct r_debug`, from `<link.h>`.
+#[cfg(target_env = "gnu")]
+#[repr(C)]
+struct RDebug {
+    _r_version: libc::c_int,
+    r_map: *const LinkMap,
+    _r_brk: usize,
+    r_state: libc::c_int,
+}
+
+/// `r_debug::r_state` when the link map isn't being changed.
+#[cfg(target_env = "gnu")]
+const RT_CONSISTENT: libc::c_int = 0;
+
+#[cfg(target_env = "gnu")]
+extern "C" {
+    /// The dynamic loader's state for debuggers, which read it without taking any lock.
+    static _r_debug: RDebug;
+}
+
+/// How long to wait for other threads to park before giving up.
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// 1 while parked threads should stay parked.
+static STOPPED: AtomicU32 = AtomicU32::new(0);
+
+/// The number of threads within `park`.
+static PARKED: AtomicU32 = AtomicU32::new(0);
+
+/// The signal used to park threads. glibc reserves the lowest real-time signals; applications
+/// conventionally allocate upward from `SIGRTMIN`, so this uses one near the top.
+fn signal() -> libc::c_int {
+    libc::SIGRTMAX() - 1
+}
+
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let ts = timeout.map(|t| libc::timespec {
+        tv_sec: t.as_secs() as libc::time_t,
+        tv_nsec: t.subsec_nanos() as libc::c_long,
+    });
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            ts.as_ref()
+                .map_or(std::ptr::null(), |t| t as *const libc::timespec),
+        )
+    };
+}
+
+fn futex_wake(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            libc::c_int::MAX,
+        )
+    };
+}
+
+/// The signal handler, which runs with all signals blocked.
+extern "C" fn park(_signal: libc::c_int) {
+    let errno = unsafe { *libc::__errno_location() };
+    PARKED.fetch_add(1, Ordering::SeqCst);
+    futex_wake(&PARKED);
+    while STOPPED.load(Ordering::SeqCst) == 1 {
+        futex_wait(&STOPPED, 1, None);
+    }
+    PARKED.fetch_sub(1, Ordering::SeqCst);
+    futex_wake(&PARKED);
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// Calls `f` with the id of each thread in this process, without allocating.
+fn for_each_task(mut f: impl FnMut(libc::pid_t)) -> Result<(), i32> {
+    let fd = unsafe {
+        libc::open(
+            b"/proc/self/task\0".as_ptr() as *const libc::c_char,
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(unsafe { *libc::__errno_location() });
+    }
+    let mut buf = [0u64; 512];
+    let result = 'read: loop {
+        let n = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                fd,
+                buf.as_mut_ptr(),
+                std::mem::size_of_val(&buf),
+            )
+        };
+        if n < 0 {
+            break Err(unsafe { *libc::__errno_location() });
+        }
+        if n == 0 {
+            break Ok(());
+        }
+        let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, n as usize) };
+
+        // Each `struct linux_dirent64` is `d_ino: u64, d_off: i64, d_reclen: u16, d_type: u8`
+        // followed by the NUL-terminated name. A record that doesn't fit is malformed.
+        let mut off = 0;
+        while off < bytes.len() {
+            if off + 19 > bytes.len() {
+                break 'read Err(libc::EIO);
+            }
+            let reclen = usize::from(u16::from_ne_bytes([bytes[off + 16], bytes[off + 17]]));
+            if reclen < 19 || off + reclen > bytes.len() {
+                break 'read Err(libc::EIO);
+            }
+            let name = &bytes[off + 19..off + reclen];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            if !name.is_empty() && name.iter().all(u8::is_ascii_digit) {
+                let tid = name.iter().fold(0, |acc: libc::pid_t, &b| {
+                    acc * 10 + libc::pid_t::from(b - b'0')
+                });
+                f(tid);
+            }
+            off += reclen;
+        }
+    };
+    unsafe { libc::close(fd) };
+    result
+}
+
+/// Checks that `infos`, as listed by `dl_iterate_phdr` before stopping, still describe the
+/// loaded objects, and that no stopped thread is loading or unloading one. Doesn't allocate.
+///
+/// This reads the link map as a debugger does, as a stopped thread may hold the loader's lock.
+/// Only the initial namespace is visible this way, so objects in any other (see `dlmopen(3)`)
+/// cause this to fail.
+#[cfg(target_env = "gnu")]
+pub(crate) fn check_objects(infos: &[libc::dl_phdr_info]) -> Result<(), Skipped> {
+    let r_debug = unsafe { &*std::ptr::addr_of!(_r_debug) };
+    if r_debug.r_state != RT_CONSISTENT {
+        return Err(Skipped::ObjectsChanging);
+    }
+    let mut map = r_debug.r_map;
+    for info in infos {
+        let Some(m) = (unsafe { map.as_ref() }) else {
+            return Err(Skipped::ObjectsChanging);
+        };
+        if m.l_addr != info.dlpi_addr as usize || m.l_name != info.dlpi_name {
+            return Err(Skipped::ObjectsChanging);
+        }
+        map = m.l_next;
+    }
+    match map.is_null() {
+        true => Ok(()),
+        false => Err(Skipped::ObjectsChanging),
+    }
+}
+
+/// Other C libraries don't export glibc's `_r_debug`, so the objects listed just before stopping
+/// are trusted unchecked.
+#[cfg(not(target_env = "gnu"))]
+pub(crate) fn check_objects(_infos: &[libc::dl_phdr_info]) -> Result<(), Skipped> {
+    Ok(())
+}
+
+/// Stops and resumes all other threads; see the module documentation.
+pub(crate) struct Stopper {
+    old_action: libc::sigaction,
+
+    /// Threads signaled by [`Stopper::stop`]; preallocated.
+    tids: Vec<libc::pid_t>,
+    stopped_at: Option<Instant>,
+}
+
+impl Stopper {
+    /// Installs the signal handler, with initial room to stop about `threads` threads.
+    pub(crate) fn new(threads: usize) -> Result<Self, Skipped> {
+        let signal = signal();
+        let mut old_action: libc::sigaction = unsafe { std::mem::zeroed() };
+        unsafe { libc::sigaction(signal, std::ptr::null(), &mut old_action) };
+        if old_action.sa_sigaction != libc::SIG_DFL && old_action.sa_sigaction != libc::SIG_IGN {
+            return Err(Skipped::StopSignalInUse(signal));
+        }
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = park as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        unsafe {
+            libc::sigfillset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+        Ok(Stopper {
+            old_action,
+            tids: Vec::with_capacity(2 * threads + 64),
+            stopped_at: None,
+        })
+    }
+
+    /// Returns the number of signaled threads which still exist.
+    fn alive(&self) -> usize {
+        let pid = unsafe { libc::getpid() };
+        self.tids
+            .iter()
+            .filter(|&&tid| unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, 0) } == 0)
+            .count()
+    }
+
+    /// Parks all other threads, including any they start meanwhile. Doesn't allocate while any
+    /// thread is parked.
+    ///
+    /// If more threads exist than there's room for, resumes them all, grows the room, and tries
+    /// again. On failure, any parked threads are resumed.
+    pub(crate) fn stop(&mut self) -> Result<(), Skipped> {
+        let signal = signal();
+        let pid = unsafe { libc::getpid() };
+        let me = unsafe { libc::gettid() };
+        let deadline = Instant::now() + TIMEOUT;
+        STOPPED.store(1, Ordering::SeqCst);
+        loop {
+            let before = self.tids.len();
+            let mut full = false;
+            let tids = &mut self.tids;
+            let listed = for_each_task(|tid| {
+                if tid == me {
+                    return;
+                }
+                // `tids` is kept sorted, so this is cheap even with many threads.
+                let Err(i) = tids.binary_search(&tid) else {
+                    return;
+                };
+                if tids.len() == tids.capacity() {
+                    full = true;
+                    return;
+                }
+                if unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, signal) } == 0 {
+                    tids.insert(i, tid);
+                }
+            });
+            if listed.is_err() {
+                self.resume();
+                return Err(Skipped::ThreadCountUnavailable);
+            }
+            if full {
+                // Nothing is parked after `resume`, so it's safe to allocate.
+                self.resume();
+                self.tids.reserve(2 * self.tids.capacity());
+                STOPPED.store(1, Ordering::SeqCst);
+                continue;
+            }
+            if self.tids.len() == before {
+                break; // Every thread is parked, so none could have started another.
+            }
+            loop {
+                let parked = PARKED.load(Ordering::SeqCst);
+                let alive = self.alive();
+                if parked as usize >= alive {
+                    break;
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    self.resume();
+                    return Err(Skipped::ThreadsNotStopped(alive - parked as usize));
+                }
+                futex_wait(
+                    &PARKED,
+                    parked,
+                    Some(std::cmp::min(deadline - now, Duration::from_millis(1))),
+                );
+            }
+        }
+        self.stopped_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Resumes parked threads, returning how long they were all stopped and how many there
+    /// were, iff [`Stopper::stop`] succeeded.
+    pub(crate) fn resume(&mut self) -> Option<(Duration, usize)> {
+        STOPPED.store(0, Ordering::SeqCst);
+        futex_wake(&STOPPED);
+        loop {
+            let parked = PARKED.load(Ordering::SeqCst);
+            if parked == 0 {
+                break;
+            }
+            futex_wait(&PARKED, parked, None);
+        }
+        let threads = self.tids.len();
+        self.tids.clear();
+        self.stopped_at.take().map(|s| (s.elapsed(), threads))
+    }
+}
+
+impl Drop for Stopper {
+    fn drop(&mut self) {
+        if STOPPED.load(Ordering::SeqCst) == 1 {
+            self.resume();
+        }
+
+        // A thread which timed out in `stop` by blocking the signal still has it pending. Ignoring
+        // the signal discards it, so that thread won't later get the old action, which for a
+        // real-time signal is by default to terminate the process.
+        let mut ignore: libc::sigaction = unsafe { std::mem::zeroed() };
+        ignore.sa_sigaction = libc::SIG_IGN;
+        unsafe {
+            libc::sigaction(signal(), &ignore, std::ptr::null_mut());
+            libc::sigaction(signal(), &self.old_action, std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::Arc;
+
+    #[test]
+    fn stop_and_resume() {
+        // Other tests block signals while priming, which would keep their threads from stopping.
+        let _guard = crate::linux::LIVE_TEST_LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let count = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        let workers: Vec<_> = (0..3)
+            .map(|_| {
+                let count = count.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        count.fetch_add(1, Ordering::SeqCst);
+                        std::thread::yield_now();
+                    }
+                })
+            })
+            .collect();
+        while count.load(Ordering::SeqCst) == 0 {
+            std::thread::yield_now();
+        }
+
+        let mut stopper = Stopper::new(4).unwrap();
+        stopper.stop().unwrap();
+        let before = count.load(Ordering::SeqCst);
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(20) {}
+        let after = count.load(Ordering::SeqCst);
+        let (_, threads) = stopper.resume().unwrap();
+        drop(stopper);
+        assert_eq!(before, after);
+        assert!(threads >= 3, "{} threads", threads);
+
+        while count.load(Ordering::SeqCst) == after {
+            std::thread::yield_now();
+        }
+        done.store(true, Ordering::SeqCst);
+        for w in workers {
+            w.join().unwrap();
+        }
+    }
+
+    /// Checks that `stop` makes room for more threads than `Stopper::new` was told of.
+    #[test]
+    fn stop_more_than_expected() {
+        let _guard = crate::linux::LIVE_TEST_LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let (senders, workers): (Vec<_>, Vec<_>) = (0..100)
+            .map(|_| {
+                let (tx, rx) = std::sync::mpsc::channel::<()>();
+                (tx, std::thread::spawn(move || rx.recv().unwrap_err()))
+            })
+            .unzip();
+
+        let mut stopper = Stopper::new(0).unwrap();
+        stopper.stop().unwrap();
+        let (_, threads) = stopper.resume().unwrap();
+        drop(stopper);
+        assert!(threads >= 100, "{} threads", threads);
+
+        drop(senders);
+        for w in workers {
+            w.join().unwrap();
+        }
+    }
+
+    /// Checks that a thread blocking the signal makes `stop` time out, and that the signal left
+    /// pending on it is discarded rather than later terminating the process.
+    #[test]
+    fn blocked_signal() {
+        let _guard = crate::linux::LIVE_TEST_LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let (blocked_tx, blocked_rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        let worker = std::thread::spawn(move || {
+            let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+            unsafe {
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, signal());
+                libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+            }
+            blocked_tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+            unsafe { libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut()) };
+        });
+        blocked_rx.recv().unwrap();
+
+        let mut stopper = Stopper::new(1).unwrap();
+        let start = Instant::now();
+        assert_eq!(stopper.stop(), Err(Skipped::ThreadsNotStopped(1)));
+        assert!(start.elapsed() >= TIMEOUT);
+        assert_eq!(PARKED.load(Ordering::SeqCst), 0);
+        drop(stopper);
+
+        done_tx.send(()).unwrap();
+        worker.join().unwrap();
+    }
+
+    /// Checks that the link map matches `dl_iterate_phdr` while nothing is being loaded.
+    #[test]
+    #[cfg(target_env = "gnu")]
+    fn objects_unchanged() {
+        let _guard = crate::linux::LIVE_TEST_LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let infos = crate::linux::loaded_infos();
+        assert!(infos.len() > 1);
+        check_objects(&infos).unwrap();
+        assert_eq!(
+            check_objects(&infos[..infos.len() - 1]),
+            Err(Skipped::ObjectsChanging)
+        );
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The system calls used to remap and lock, behind a trait so they can be simulated in tests.
+//!
+//! Errors are returned as `errno` values. Implementations must not allocate; see
+//! `linux::Context`.
+
+use std::ops::Range;
+
+/// The memory management operations used by priming.
+///
+/// SAFETY: as with the underlying system calls, callers must ensure the affected ranges aren't
+/// in use by anything else.
+pub(crate) trait Sys {
+    /// `mmap(addr, len, prot, flags, fd, 0)`; `addr` may be 0.
+    unsafe fn mmap(
+        &self,
+        addr: usize,
+        len: usize,
+        prot: libc::c_int,
+        flags: libc::c_int,
+        fd: libc::c_int,
+    ) -> Result<usize, i32>;
+
+    unsafe fn munmap(&self, range: Range<usize>);
+
+    /// `mremap(old, len, len, MREMAP_MAYMOVE | MREMAP_FIXED, new)`.
+    unsafe fn mremap_fixed(&self, old: usize, len: usize, new: usize) -> Result<(), i32>;
+
+    unsafe fn mprotect(&self, range: Range<usize>, prot: libc::c_int) -> Result<(), i32>;
+
+    unsafe fn madvise(&self, range: Range<usize>, advice: libc::c_int) -> Result<(), i32>;
+
+    /// `mlock(range)`, or `mlock2(range, MLOCK_ONFAULT)` iff `on_fault`.
+    unsafe fn mlock(&self, range: Range<usize>, on_fault: bool) -> Result<(), i32>;
+
+    /// `mlockall(flags)`.
+    unsafe fn mlockall(&self, flags: libc::c_int) -> Result<(), i32>;
+
+    /// Copies `len` bytes from `src` to `dst`, which must both be mapped.
+    unsafe fn copy(&self, dst: usize, src: usize, len: usize);
+
+    /// Names an anonymous mapping via `PR_SET_VMA_ANON_NAME`, best-effort.
+    unsafe fn name_anon(&self, range: Range<usize>, name: *const u8);
+
+    unsafe fn memfd_create(
+        &self,
+        name: *const libc::c_char,
+        flags: libc::c_uint,
+    ) -> Result<libc::c_int, i32>;
+
+    unsafe fn ftruncate(&self, fd: libc::c_int, len: usize) -> Result<(), i32>;
+
+    unsafe fn close(&self, fd: libc::c_int);
+}
+
+fn errno() -> i32 {
+    unsafe { (*libc::__errno_location()) as i32 }
+}
+
+/// The running kernel.
+pub(crate) struct Real;
+
+impl Sys for Real {
+    unsafe fn mmap(
+        &self,
+        addr: usize,
+        len: usize,
+        prot: libc::c_int,
+        flags: libc::c_int,
+        fd: libc::c_int,
+    ) -> Result<usize, i32> {
+        match libc::mmap(addr as *mut libc::c_void, len, prot, flags, fd, 0) {
+            libc::MAP_FAILED => Err(errno()),
+            a => Ok(a as usize),
+        }
+    }
+
+    unsafe fn munmap(&self, range: Range<usize>) {
+        libc::munmap(range.start as *mut libc::c_void, range.len());
+    }
+
+    unsafe fn mremap_fixed(&self, old: usize, len: usize, new: usize) -> Result<(), i32> {
+        match libc::mremap(
+            old as *mut libc::c_void,
+            len,
+            len,
+            libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED,
+            new as *mut libc::c_void,
+        ) {
+            libc::MAP_FAILED => Err(errno()),
+            _ => Ok(()),
+        }
+    }
+
+    unsafe fn mprotect(&self, range: Range<usize>, prot: libc::c_int) -> Result<(), i32> {
+        match libc::mprotect(range.start as *mut libc::c_void, range.len(), prot) {
+            -1 => Err(errno()),
+            _ => Ok(()),
+        }
+    }
+
+    unsafe fn madvise(&self, range: Range<usize>, advice: libc::c_int) -> Result<(), i32> {
+        match libc::madvise(range.start as *mut libc::c_void, range.len(), advice) {
+            -1 => Err(errno()),
+            _ => Ok(()),
+        }
+    }
+
+    unsafe fn mlock(&self, range: Range<usize>, on_fault: bool) -> Result<(), i32> {
+        // glibc only wraps `mlock2` since 2.27, so call it directly.
+        let ret = match on_fault {
+            false => libc::mlock(range.start as *const libc::c_void, range.len()),
+            true => libc::syscall(
+                libc::SYS_mlock2,
+                range.start,
+                range.len(),
+                libc::MLOCK_ONFAULT,
+            ) as libc::c_int,
+        };
+        match ret {
+            -1 => Err(errno()),
+            _ => Ok(()),
+        }
+    }
+
+    unsafe fn mlockall(&self, flags: libc::c_int) -> Result<(), i32> {
+        match libc::mlockall(flags) {
+            -1 => Err(errno()),
+            _ => Ok(()),
+        }
+    }
+
+    unsafe fn copy(&self, dst: usize, src: usize, len: usize) {
+        libc::memcpy(dst as *mut libc::c_void, src as *const libc::c_void, len);
+    }
+
+    unsafe fn name_anon(&self, range: Range<usize>, name: *const u8) {
+        libc::prctl(
+            libc::PR_SET_VMA,
+            libc::PR_SET_VMA_ANON_NAME as libc::c_ulong,
+            range.start as libc::c_ulong,
+            range.len() as libc::c_ulong,
+            name,
+        );
+    }
+
+    unsafe fn memfd_create(
+        &self,
+        name: *const libc::c_char,
+        flags: libc::c_uint,
+    ) -> Result<libc::c_int, i32> {
+        match libc::memfd_create(name, flags) {
+            -1 => Err(errno()),
+            fd => Ok(fd),
+        }
+    }
+
+    unsafe fn ftruncate(&self, fd: libc::c_int, len: usize) -> Result<(), i32> {
+        match libc::ftruncate(fd, len as libc::off_t) {
+            -1 => Err(errno()),
+            _ => Ok(()),
+        }
+    }
+
+    unsafe fn close(&self, fd: libc::c_int) {
+        libc::close(fd);
+    }
+}
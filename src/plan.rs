@@ -0,0 +1,463 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Range arithmetic for remapping and locking, separated from its side effects.
+//!
+//! Priming uses [`plan_replacement`] with a callback which actually reserves address space;
+//! [`crate::Options::plan`] uses it with one which only consults a snapshot of the memory map.
+
+use crate::report::{DebugProt, HugeError, ObjectReport, PF_R, PF_W};
+use crate::RemapStrategy;
+use std::ops::Range;
+
+pub(crate) fn round_up(addr: usize, mask: usize) -> usize {
+    match (addr & mask) != 0 {
+        true => (addr & !mask) + mask + 1,
+        false => addr,
+    }
+}
+
+/// A planned replacement of part of a segment at a single huge page size.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Replacement {
+    /// The huge page-aligned range to replace.
+    pub(crate) map: Range<usize>,
+
+    /// The subset of `map` to copy into the replacement.
+    pub(crate) copy: Range<usize>,
+
+    /// The free ranges at the start and end of `map` which must be reserved, if any.
+    pub(crate) reserve: [Option<Range<usize>>; 2],
+}
+
+/// Plans the replacement of `pages` at `huge_page_mask` granularity.
+///
+/// `reserve` is called for the free portion of the partial huge page at either end, in that
+/// order, and returns true iff that portion is available. Returns `None` iff no whole huge page
+/// can be replaced.
+pub(crate) fn plan_replacement(
+    pages: &Range<usize>,
+    huge_page_mask: usize,
+    mut reserve: impl FnMut(Range<usize>) -> bool,
+) -> Option<Replacement> {
+    let outer = pages.start & !huge_page_mask..round_up(pages.end, huge_page_mask);
+    let inner = round_up(pages.start, huge_page_mask)..pages.end & !huge_page_mask;
+    let mut reserved = [None, None];
+    let start = if outer.start < pages.start && reserve(outer.start..pages.start) {
+        reserved[0] = Some(outer.start..pages.start);
+        outer.start
+    } else {
+        inner.start
+    };
+    let end = if outer.end > pages.end && reserve(pages.end..outer.end) {
+        reserved[1] = Some(pages.end..outer.end);
+        outer.end
+    } else {
+        inner.end
+    };
+    if start >= end {
+        return None;
+    }
+    Some(Replacement {
+        map: start..end,
+        copy: std::cmp::max(start, pages.start)..std::cmp::min(end, pages.end),
+        reserve: reserved,
+    })
+}
+
+/// Parameters for [`Planner::plan`], shared by all segments.
+pub(crate) struct Planner<'a> {
+    pub(crate) base_page_size: usize,
+
+    /// The usable huge page sizes, largest first, as in `linux::Context::huge_page_masks`.
+    pub(crate) huge_page_sizes: &'a [usize],
+    pub(crate) large_page_size: Option<usize>,
+
+    /// The strategy to plan for, iff remapping.
+    pub(crate) strategy: Option<RemapStrategy>,
+    pub(crate) mlock: bool,
+}
+
+impl Planner<'_> {
+    /// Plans a single segment, assuming every operation succeeds.
+    ///
+    /// `is_free` returns true iff the given range is unmapped.
+    pub(crate) fn plan(
+        &self,
+        object_i: usize,
+        flags: u32,
+        addrs: Range<usize>,
+        mut is_free: impl FnMut(Range<usize>) -> bool,
+    ) -> SegmentPlan {
+        let base_page_mask = self.base_page_size - 1;
+        let pages = (addrs.start & !base_page_mask)..round_up(addrs.end, base_page_mask);
+        let mut plan = SegmentPlan {
+            object_i,
+            flags,
+            addrs,
+            remap_strategy: self.strategy,
+            remap: None,
+            reserve: Vec::new(),
+            copy: Vec::new(),
+            large_pages: None,
+            mthp_size: None,
+            lock: None,
+            deprioritized: false,
+        };
+        if let Some(strategy) = self.strategy {
+            plan.remap = Some(self.plan_remap(strategy, &pages, &mut plan, &mut is_free));
+        }
+        if self.mlock {
+            plan.lock = Some(match plan.remapped() {
+                Some(r) => std::cmp::min(r.start, pages.start)..std::cmp::max(r.end, pages.end),
+                None => pages,
+            });
+        }
+        plan
+    }
+
+    /// Mirrors `linux::Segment::remap` for a single strategy.
+    fn plan_remap(
+        &self,
+        strategy: RemapStrategy,
+        pages: &Range<usize>,
+        plan: &mut SegmentPlan,
+        is_free: &mut impl FnMut(Range<usize>) -> bool,
+    ) -> Result<Range<usize>, HugeError> {
+        if (plan.flags & PF_R) == 0 {
+            return Err(HugeError::Unreadable);
+        }
+        if (plan.flags & PF_W) != 0 {
+            return Err(HugeError::Writable);
+        }
+        let huge_page_mask = self.huge_page_sizes[0] - 1;
+        let mut replace = |plan: &mut SegmentPlan, pages: &Range<usize>, mask: usize| {
+            let r = plan_replacement(pages, mask, &mut *is_free)?;
+            plan.reserve
+                .extend(IntoIterator::into_iter(r.reserve).flatten());
+            plan.copy.push(r.copy);
+            Some(r.map)
+        };
+        match (strategy, self.large_page_size) {
+            (RemapStrategy::Collapse, _) => {
+                let inner = round_up(pages.start, huge_page_mask)..pages.end & !huge_page_mask;
+                match inner.is_empty() {
+                    true => Err(HugeError::TooSmall),
+                    false => Ok(inner),
+                }
+            }
+            (RemapStrategy::HugetlbMemfd, Some(large_page_size)) => {
+                let mask = large_page_size - 1;
+                let large = round_up(pages.start, mask)..pages.end & !mask;
+                if large.is_empty() {
+                    return replace(plan, pages, huge_page_mask).ok_or(HugeError::Conflict);
+                }
+                plan.copy.push(large.clone());
+                plan.large_pages = Some(large.clone());
+                let start = match pages.start < large.start {
+                    true => replace(plan, &(pages.start..large.start), huge_page_mask)
+                        .map_or(large.start, |r| r.start),
+                    false => large.start,
+                };
+                let end = match large.end < pages.end {
+                    true => replace(plan, &(large.end..pages.end), huge_page_mask)
+                        .map_or(large.end, |r| r.end),
+                    false => large.end,
+                };
+                Ok(start..end)
+            }
+            (RemapStrategy::AnonThp, _) => {
+                for (i, &size) in self.huge_page_sizes.iter().enumerate() {
+                    if let Some(map) = replace(plan, pages, size - 1) {
+                        plan.mthp_size = (i > 0).then_some(size);
+                        return Ok(map);
+                    }
+                }
+                Err(HugeError::Conflict)
+            }
+            _ => replace(plan, pages, huge_page_mask).ok_or(HugeError::Conflict),
+        }
+    }
+}
+
+/// A description of what [`crate::Options::run`] would do, as returned by
+/// [`crate::Options::plan`].
+///
+/// Planning assumes the first available remap strategy and every operation succeed; e.g. it
+/// can't know whether the hugetlb pool will run out partway through.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Plan {
+    /// The platform's base page size, or 0 if not determined.
+    pub base_page_size: usize,
+
+    /// The huge page size to remap with, iff remapping is planned.
+    pub huge_page_size: Option<usize>,
+
+    /// As in [`crate::Report::large_page_size`].
+    pub large_page_size: Option<usize>,
+
+    /// As in [`crate::Report::mthp_sizes`].
+    pub mthp_sizes: Vec<usize>,
+
+    /// The available remap strategies, in order. Only the first is planned.
+    pub remap_strategies: Vec<RemapStrategy>,
+
+    /// As in [`crate::Report::budget`].
+    pub budget: Option<usize>,
+
+    /// All ELF objects, in `dl_iterate_phdr` order. The main executable is first.
+    pub objects: Vec<ObjectReport>,
+
+    /// All `PT_LOAD` segments, in `dl_iterate_phdr` order.
+    pub segments: Vec<SegmentPlan>,
+}
+
+/// A description of what would be done to a single `PT_LOAD` segment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct SegmentPlan {
+    /// The index of the containing object within [`Plan::objects`].
+    pub object_i: usize,
+
+    /// The ELF `p_flags` of the segment.
+    pub flags: u32,
+
+    /// The virtual address range as described by the ELF headers.
+    pub addrs: Range<usize>,
+
+    /// The strategy planned, iff remapping.
+    pub remap_strategy: Option<RemapStrategy>,
+
+    /// The range which would be remapped, or why it wouldn't be, iff remapping.
+    pub remap: Option<Result<Range<usize>, HugeError>>,
+
+    /// Free ranges which would be reserved as padding.
+    pub reserve: Vec<Range<usize>>,
+
+    /// Ranges which would be copied into newly allocated memory.
+    pub copy: Vec<Range<usize>>,
+
+    /// As in [`crate::SegmentReport::large_pages`].
+    pub large_pages: Option<Range<usize>>,
+
+    /// As in [`crate::SegmentReport::mthp_size`].
+    pub mthp_size: Option<usize>,
+
+    /// The range which would be locked, iff locking.
+    pub lock: Option<Range<usize>>,
+
+    /// As in [`crate::SegmentReport::deprioritized`].
+    pub deprioritized: bool,
+}
+
+impl SegmentPlan {
+    /// Returns the range which would be remapped, if any.
+    #[inline]
+    pub fn remapped(&self) -> Option<&Range<usize>> {
+        self.remap.as_ref().and_then(|r| r.as_ref().ok())
+    }
+
+    /// Returns the bytes of newly allocated memory the remapped range would occupy.
+    ///
+    /// This is zero for [`RemapStrategy::Collapse`], which keeps text in the page cache.
+    pub fn added_bytes(&self) -> usize {
+        match (self.remap_strategy, self.remapped()) {
+            (Some(RemapStrategy::Collapse), _) | (_, None) => 0,
+            (_, Some(r)) => r.len(),
+        }
+    }
+}
+
+impl Plan {
+    /// Returns the total bytes which would be locked.
+    pub fn locked_bytes(&self) -> usize {
+        self.segments
+            .iter()
+            .filter_map(|s| s.lock.as_ref())
+            .map(Range::len)
+            .sum()
+    }
+
+    /// Returns the total bytes which would be remapped, including padding.
+    pub fn remapped_bytes(&self) -> usize {
+        self.segments
+            .iter()
+            .filter_map(|s| s.remapped())
+            .map(Range::len)
+            .sum()
+    }
+
+    /// Returns the total bytes of padding which would be reserved.
+    pub fn padding_bytes(&self) -> usize {
+        self.segments
+            .iter()
+            .flat_map(|s| s.reserve.iter())
+            .map(Range::len)
+            .sum()
+    }
+
+    /// Returns the total bytes which would be copied.
+    pub fn copy_bytes(&self) -> usize {
+        self.segments
+            .iter()
+            .flat_map(|s| s.copy.iter())
+            .map(Range::len)
+            .sum()
+    }
+
+    /// Returns the total bytes of newly allocated memory remapping would occupy: the estimated
+    /// RAM cost of remapping.
+    pub fn added_bytes(&self) -> usize {
+        self.segments.iter().map(SegmentPlan::added_bytes).sum()
+    }
+}
+
+/// Writes a human-readable description, in the style of [`crate::Report`]'s.
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "planned pages:")?;
+        let mut last_object_i = None;
+        for seg in &self.segments {
+            if Some(seg.object_i) != last_object_i {
+                if let Some(obj) = self.objects.get(seg.object_i) {
+                    writeln!(f, "object {}:", obj.path.display())?;
+                }
+            }
+            write!(
+                f,
+                "* {:012x}-{:012x} {} ->",
+                seg.addrs.start,
+                seg.addrs.end,
+                DebugProt(seg.flags)
+            )?;
+            match seg.remap.as_ref() {
+                Some(Ok(r)) => write!(f, " remap={:012x}-{:012x}", r.start, r.end)?,
+                Some(Err(e)) => write!(f, " remap={e}")?,
+                None => {}
+            }
+            for r in &seg.reserve {
+                write!(f, " reserve={:012x}-{:012x}", r.start, r.end)?;
+            }
+            if let Some(s) = seg.mthp_size {
+                write!(f, " mthp={s}")?;
+            }
+            if let Some(large) = seg.large_pages.as_ref() {
+                write!(f, " large={:012x}-{:012x}", large.start, large.end)?;
+            }
+            if let Some(l) = seg.lock.as_ref() {
+                write!(f, " mlock={:012x}-{:012x}", l.start, l.end)?;
+            }
+            if seg.deprioritized {
+                write!(f, " deprioritized")?;
+            }
+            writeln!(f)?;
+            last_object_i = Some(seg.object_i);
+        }
+        if let Some(s) = self.remap_strategies.first() {
+            write!(f, "strategy: {s}; ")?;
+        }
+        write!(
+            f,
+            "totals: locked={} remapped={} padding={} copied={} added={}",
+            self.locked_bytes(),
+            self.remapped_bytes(),
+            self.padding_bytes(),
+            self.copy_bytes(),
+            self.added_bytes()
+        )?;
+        if let Some(b) = self.budget {
+            let deprioritized = self.segments.iter().filter(|s| s.deprioritized).count();
+            write!(f, " budget={b} deprioritized={deprioritized}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::PF_X;
+
+    const BASE: usize = 0x1000;
+    const HUGE: usize = 0x4000;
+
+    fn is_free(occupied: &[Range<usize>], r: &Range<usize>) -> bool {
+        occupied
+            .iter()
+            .all(|o| o.end <= r.start || r.end <= o.start)
+    }
+
+    /// Checks invariants of [`plan_replacement`] on every layout of a segment within four huge
+    /// pages of four base pages each, with optional neighbours directly before and after.
+    #[test]
+    fn replacement_layouts() {
+        let mask = HUGE - 1;
+        for start in 0..16 {
+            for end in start + 1..=16 {
+                for (before, after) in [(false, false), (true, false), (false, true), (true, true)]
+                {
+                    let pages = start * BASE..end * BASE;
+                    let mut occupied = vec![pages.clone()];
+                    if before && start > 0 {
+                        occupied.push(pages.start - BASE..pages.start);
+                    }
+                    if after && end < 16 {
+                        occupied.push(pages.end..pages.end + BASE);
+                    }
+                    let r = plan_replacement(&pages, mask, |r| is_free(&occupied, &r));
+                    let inner = round_up(pages.start, mask)..pages.end & !mask;
+                    let Some(r) = r else {
+                        assert!(inner.is_empty(), "{:x?} {:x?}", pages, occupied);
+                        continue;
+                    };
+                    assert_eq!(r.map.start & mask, 0);
+                    assert_eq!(r.map.end & mask, 0);
+                    assert!(r.map.start <= inner.start && inner.end <= r.map.end);
+                    assert!(pages.start <= r.copy.start && r.copy.end <= pages.end);
+                    assert!(r.map.start <= r.copy.start && r.copy.end <= r.map.end);
+                    let mut covered = r.copy.len();
+                    for res in r.reserve.iter().flatten() {
+                        assert!(is_free(&occupied, res));
+                        assert!(r.map.start <= res.start && res.end <= r.map.end);
+                        covered += res.len();
+                    }
+                    assert_eq!(covered, r.map.len(), "{pages:x?} {occupied:x?} {r:x?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn segment() {
+        let sizes = [HUGE, HUGE / 2];
+        let planner = Planner {
+            base_page_size: BASE,
+            huge_page_sizes: &sizes,
+            large_page_size: None,
+            strategy: Some(RemapStrategy::AnonThp),
+            mlock: true,
+        };
+        let occupied = [0x3000..0x4000, 0x5000..0x7000, 0x8000..0x9000];
+        let plan = planner.plan(0, PF_R | PF_X, 0x5000..0x6800, |r| is_free(&occupied, &r));
+        assert_eq!(plan.remap, Some(Ok(0x4000..0x8000)));
+        assert_eq!(plan.reserve, vec![0x4000..0x5000, 0x7000..0x8000]);
+        assert_eq!(plan.copy, vec![0x5000..0x7000]);
+        assert_eq!(plan.mthp_size, None);
+        assert_eq!(plan.lock, Some(0x4000..0x8000));
+        assert_eq!(plan.added_bytes(), HUGE);
+
+        // A neighbour within the only partial huge page: fall back to the smaller size.
+        let occupied = [0x4000..0x7000, 0x7000..0x8000];
+        let plan = planner.plan(0, PF_R | PF_X, 0x4000..0x7000, |r| is_free(&occupied, &r));
+        assert_eq!(plan.remap, Some(Ok(0x4000..0x6000)));
+        assert_eq!(plan.reserve, Vec::new());
+        assert_eq!(plan.mthp_size, Some(HUGE / 2));
+
+        let plan = planner.plan(0, PF_R | PF_W, 0x4000..0x7000, |_| true);
+        assert_eq!(plan.remap, Some(Err(HugeError::Writable)));
+        assert_eq!(plan.lock, Some(0x4000..0x7000));
+    }
+}
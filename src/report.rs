@@ -0,0 +1,764 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Structured description of what priming did.
+
+use crate::{Action, LockMode, RemapStrategy};
+use std::io::Error;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use std::path::Path;
+
+// ELF protection flags, as defined by the ELF specification on all platforms.
+pub(crate) const PF_X: u32 = 1 << 0;
+pub(crate) const PF_W: u32 = 1 << 1;
+pub(crate) const PF_R: u32 = 1 << 2;
+
+/// A structured description of what [`crate::Options::run`] did.
+///
+/// Unlike the text produced by [`crate::Output::log`], this is suitable for programmatic checks,
+/// e.g. asserting at startup that the main executable's text was remapped.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Report {
+    /// The id of the process which was primed.
+    pub pid: u32,
+
+    /// Why priming was skipped entirely, if it was.
+    pub skipped: Option<Skipped>,
+
+    /// Why remapping was skipped while locking proceeded, if it was.
+    pub remap_skipped: Option<Skipped>,
+
+    /// Signals which arrived while priming, in numeric order. All blockable signals are
+    /// blocked on the priming thread meanwhile, so these were delivered afterward (unless another
+    /// thread took them).
+    pub signals_deferred: Vec<i32>,
+
+    /// How long other threads were stopped, iff [`crate::Options::stop_the_world`] stopped them.
+    pub world_stopped: Option<Duration>,
+
+    /// The platform's base page size, or 0 if priming was skipped before it was determined.
+    pub base_page_size: usize,
+
+    /// The huge page size used for remapping, iff remapping was attempted.
+    pub huge_page_size: Option<usize>,
+
+    /// The remap strategies attempted, in order; empty iff remapping wasn't attempted.
+    ///
+    /// This excludes requested strategies which probing found unavailable.
+    pub remap_strategies: Vec<RemapStrategy>,
+
+    /// The larger hugetlb page size used by [`RemapStrategy::HugetlbMemfd`], iff one was
+    /// requested via [`crate::Options::hugetlb_page_size`] and available.
+    pub large_page_size: Option<usize>,
+
+    /// The enabled multi-size THP (mTHP) sizes smaller than `huge_page_size`, largest first,
+    /// which [`RemapStrategy::AnonThp`] falls back to on conflict.
+    pub mthp_sizes: Vec<usize>,
+
+    /// All ELF objects visited, in `dl_iterate_phdr` order. The main executable is first.
+    pub objects: Vec<ObjectReport>,
+
+    /// All `PT_LOAD` segments visited, in `dl_iterate_phdr` order.
+    pub segments: Vec<SegmentReport>,
+
+    /// The number of segments which were primed but could not be recorded.
+    ///
+    /// This should always be 0; it would be non-zero only if the set of loaded objects changed
+    /// while priming.
+    pub segments_dropped: usize,
+
+    /// The process's total locked memory in bytes (`VmLck` from `/proc/self/status`), iff
+    /// verification was requested and succeeded.
+    pub vm_lck: Option<usize>,
+
+    /// The `RLIMIT_MEMLOCK` check made before locking, iff locking was requested.
+    pub memlock: Option<Memlock>,
+
+    /// The budget in bytes, iff [`crate::Options::budget`] was set and resolved to a limit.
+    pub budget: Option<usize>,
+}
+
+/// How `RLIMIT_MEMLOCK` applied to locking; see [`Report::memlock`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Memlock {
+    /// The bytes needed to lock the segments selected for locking, in whole pages.
+    ///
+    /// Segments which [`crate::Options::filter`]s exclude from locking or which don't fit within
+    /// [`Report::budget`] aren't counted. Before locking, this is estimated from the `PT_LOAD`
+    /// headers, counting remapped segments in whole huge pages; afterward, it's the total of
+    /// [`SegmentReport::lock_range`] for every segment where locking was attempted.
+    ///
+    /// [`crate::LockMode::All`] and [`crate::LockMode::AllOnFault`] also lock the heap, stacks,
+    /// and other mappings, so for them it's `VmSize` less `already_locked`, both before and after.
+    pub needed: usize,
+
+    /// The bytes already locked beforehand (`VmLck`), which also count against the limit.
+    pub already_locked: usize,
+
+    /// The soft limit before priming, or `None` if unlimited.
+    pub soft_limit: Option<u64>,
+
+    /// The hard limit, or `None` if unlimited.
+    pub hard_limit: Option<u64>,
+
+    /// True iff the soft limit was too low and so was raised to the hard limit.
+    pub raised: bool,
+
+    /// True iff the process has `CAP_IPC_LOCK`, so the limit doesn't apply.
+    pub cap_ipc_lock: bool,
+
+    /// The bytes by which the limit fell short of `already_locked + needed`, or 0 if it
+    /// sufficed.
+    pub shortfall: usize,
+}
+
+impl Memlock {
+    /// Returns the limit in effect for locking, or `None` if unlimited.
+    pub fn effective_limit(&self) -> Option<u64> {
+        match (self.cap_ipc_lock, self.raised) {
+            (true, _) => None,
+            (false, true) => self.hard_limit,
+            (false, false) => self.soft_limit,
+        }
+    }
+
+    /// Returns the smallest limit which would have sufficed, e.g. for systemd's `LimitMEMLOCK=`.
+    pub fn required_limit(&self) -> usize {
+        self.already_locked + self.needed
+    }
+
+    pub(crate) fn update_shortfall(&mut self) {
+        self.shortfall = match self.effective_limit() {
+            Some(l) => (self.required_limit() as u64).saturating_sub(l) as usize,
+            None => 0,
+        };
+    }
+}
+
+/// The reason priming was skipped entirely.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum Skipped {
+    /// There were this many threads running; priming requires exactly 1.
+    ThreadsRunning(usize),
+
+    /// The thread count could not be determined.
+    ThreadCountUnavailable,
+
+    /// No operations were requested (or all requested operations were unavailable).
+    NothingToDo,
+
+    /// [`crate::prime_new_objects`] found no objects loaded since the last run.
+    NoNewObjects,
+
+    /// [`crate::Options::stop_the_world`] couldn't install its handler for this signal, which
+    /// already has one.
+    StopSignalInUse(i32),
+
+    /// [`crate::Options::stop_the_world`] timed out waiting for this many threads to stop, e.g.
+    /// because they block its signal.
+    ThreadsNotStopped(usize),
+
+    /// [`crate::Options::stop_the_world`] found the loaded objects changing once other threads
+    /// stopped, e.g. because one was stopped within `dlopen` or `dlclose`.
+    ObjectsChanging,
+}
+
+impl std::fmt::Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Skipped::ThreadsRunning(t) => write!(f, "there are {t} threads running; must be 1"),
+            Skipped::ThreadCountUnavailable => write!(f, "unable to get thread count"),
+            Skipped::NothingToDo => write!(f, "no page priming operations to perform"),
+            Skipped::NoNewObjects => write!(f, "no objects loaded since the last run"),
+            Skipped::StopSignalInUse(s) => {
+                write!(f, "stop-the-world signal {s} already has a handler")
+            }
+            Skipped::ThreadsNotStopped(t) => write!(f, "{t} threads didn't stop in time"),
+            Skipped::ObjectsChanging => write!(f, "loaded objects were changing"),
+        }
+    }
+}
+
+/// An ELF object (the main executable or a shared library) visited while priming.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ObjectReport {
+    /// The index of this object within [`Report::objects`].
+    pub index: usize,
+
+    /// The object's path, as reported by the dynamic loader (or `current_exe` for the main
+    /// executable). May be empty, e.g. for the vDSO.
+    pub path: PathBuf,
+
+    /// The load bias: the difference between the object's virtual addresses in memory and those
+    /// in its ELF file.
+    pub load_bias: usize,
+
+    /// True iff this is the main executable.
+    pub is_main: bool,
+}
+
+/// An ELF `PT_LOAD` segment visited while priming.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct SegmentReport {
+    /// The index of the owning object within [`Report::objects`].
+    pub object_i: usize,
+
+    /// The ELF `p_flags` (`PF_R`, `PF_W`, `PF_X`).
+    pub flags: u32,
+
+    /// The virtual address range, not rounded to page boundaries.
+    pub addrs: Range<usize>,
+
+    /// The result of remapping into huge pages, iff attempted.
+    ///
+    /// On success, this is the remapped range, which is aligned to huge pages and may include
+    /// padding outside `addrs`.
+    pub remap: Option<Result<Range<usize>, HugeError>>,
+
+    /// The strategy which produced `remap`, iff a strategy was tried.
+    pub remap_strategy: Option<RemapStrategy>,
+
+    /// Strategies which failed before `remap_strategy` was tried. This includes any failure of
+    /// [`RemapStrategy::HugetlbMemfd`] with pages of [`Report::large_page_size`], after which it
+    /// was retried with regular huge pages.
+    pub remap_fallbacks: Fallbacks,
+
+    /// The part of `remap` backed by pages of [`Report::large_page_size`], iff any.
+    pub large_pages: Option<Range<usize>>,
+
+    /// The mTHP size used by `remap`, iff smaller than [`Report::huge_page_size`] because
+    /// mappings conflicted within every relevant huge page.
+    pub mthp_size: Option<usize>,
+
+    /// The result of `mlock`, iff attempted. On failure, this is the `errno` value.
+    pub mlock: Option<Result<(), i32>>,
+
+    /// How `mlock` was attempted, iff it was.
+    pub mlock_mode: Option<LockMode>,
+
+    /// True iff this segment was neither locked nor remapped because it didn't fit within
+    /// [`Report::budget`].
+    pub deprioritized: bool,
+
+    /// The combined decision of [`crate::Options::filter`]s, iff any were set.
+    pub filter: Option<Action>,
+
+    /// The kernel's view of the remapped range (or, if not remapped, the locked range), iff
+    /// verification was requested and succeeded.
+    pub coverage: Option<Coverage>,
+}
+
+/// Remap strategies which failed for a segment before the final one, in the order tried.
+///
+/// This has fixed capacity so it can be filled without allocating while priming.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fallbacks([Option<(RemapStrategy, HugeError)>; 4]);
+
+impl Fallbacks {
+    pub(crate) fn push(&mut self, strategy: RemapStrategy, error: HugeError) {
+        if let Some(slot) = self.0.iter_mut().find(|s| s.is_none()) {
+            *slot = Some((strategy, error));
+        }
+    }
+
+    /// Iterates through the failed strategies and their errors.
+    pub fn iter(&self) -> impl Iterator<Item = (RemapStrategy, HugeError)> + '_ {
+        self.0.iter().map_while(|s| *s)
+    }
+
+    /// Returns true iff no strategy failed before the final one.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0[0].is_none()
+    }
+}
+
+/// The kernel's view of a range's backing pages, as described by `/proc/self/smaps`.
+///
+/// Values are in bytes, summed over every mapping overlapping the range. Each overlapping
+/// mapping is counted in full, even if it extends outside the range.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Coverage {
+    /// The distinct `KernelPageSize` values of overlapping mappings, in ascending order.
+    pub kernel_page_sizes: Vec<usize>,
+
+    /// `Private_Hugetlb`: hugetlbfs pages mapped only by this process.
+    pub private_hugetlb: usize,
+
+    /// `Shared_Hugetlb`: hugetlbfs pages also mapped by other processes.
+    pub shared_hugetlb: usize,
+
+    /// `AnonHugePages`: anonymous memory backed by transparent huge pages.
+    pub anon_huge_pages: usize,
+
+    /// `ShmemPmdMapped`: shmem (including memfd) mapped with PMD-sized huge pages.
+    pub shmem_pmd_mapped: usize,
+
+    /// `FilePmdMapped`: page cache mapped with PMD-sized huge pages.
+    pub file_pmd_mapped: usize,
+
+    /// `Locked`: resident and `mlock`ed memory.
+    pub locked: usize,
+}
+
+impl Coverage {
+    /// Returns the bytes backed by huge pages of any kind.
+    pub fn huge_bytes(&self) -> usize {
+        self.private_hugetlb
+            + self.shared_hugetlb
+            + self.anon_huge_pages
+            + self.shmem_pmd_mapped
+            + self.file_pmd_mapped
+    }
+}
+
+impl SegmentReport {
+    /// Returns a report of a segment of object 0 on which nothing was attempted, for tests to
+    /// fill in.
+    #[cfg(test)]
+    pub(crate) fn for_test(flags: u32, addrs: Range<usize>) -> Self {
+        SegmentReport {
+            object_i: 0,
+            flags,
+            addrs,
+            remap: None,
+            remap_strategy: None,
+            remap_fallbacks: Fallbacks::default(),
+            large_pages: None,
+            mthp_size: None,
+            mlock: None,
+            mlock_mode: None,
+            deprioritized: false,
+            filter: None,
+            coverage: None,
+        }
+    }
+
+    /// Returns true iff the segment has `PF_R`.
+    #[inline]
+    pub fn is_readable(&self) -> bool {
+        (self.flags & PF_R) != 0
+    }
+
+    /// Returns true iff the segment has `PF_W`.
+    #[inline]
+    pub fn is_writable(&self) -> bool {
+        (self.flags & PF_W) != 0
+    }
+
+    /// Returns true iff the segment has `PF_X`.
+    #[inline]
+    pub fn is_executable(&self) -> bool {
+        (self.flags & PF_X) != 0
+    }
+
+    /// Returns the address range rounded out to the given base page size.
+    pub fn page_range(&self, base_page_size: usize) -> Range<usize> {
+        let mask = base_page_size.wrapping_sub(1);
+        (self.addrs.start & !mask)..((self.addrs.end + mask) & !mask)
+    }
+
+    /// Returns the range `mlock` applies to: the page range, extended to cover the remapped
+    /// range if any.
+    pub fn lock_range(&self, base_page_size: usize) -> Range<usize> {
+        let pages = self.page_range(base_page_size);
+        match self.remapped() {
+            Some(r) => std::cmp::min(r.start, pages.start)..std::cmp::max(r.end, pages.end),
+            None => pages,
+        }
+    }
+
+    /// Returns the successfully remapped range, if any.
+    #[inline]
+    pub fn remapped(&self) -> Option<&Range<usize>> {
+        self.remap.as_ref().and_then(|r| r.as_ref().ok())
+    }
+
+    /// Returns true iff neither a filter nor the budget excluded this segment from locking.
+    pub(crate) fn lock_wanted(&self) -> bool {
+        !self.deprioritized && self.filter.map_or(true, |a| a.mlock)
+    }
+
+    /// Returns true iff neither a filter nor the budget excluded this segment from remapping.
+    pub(crate) fn remap_wanted(&self) -> bool {
+        !self.deprioritized && self.filter.map_or(true, |a| a.remap)
+    }
+
+    /// Returns true iff `mlock` was attempted and succeeded.
+    #[inline]
+    pub fn locked(&self) -> bool {
+        matches!(self.mlock, Some(Ok(())))
+    }
+}
+
+impl Report {
+    /// Returns the segments belonging to the given object.
+    pub fn object_segments(&self, object_i: usize) -> impl Iterator<Item = &SegmentReport> {
+        self.segments.iter().filter(move |s| s.object_i == object_i)
+    }
+
+    /// Returns the total bytes successfully locked, in whole pages.
+    pub fn locked_bytes(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|s| s.locked())
+            .map(|s| s.lock_range(self.base_page_size).len())
+            .sum()
+    }
+
+    /// Returns the segments skipped to fit within [`Report::budget`].
+    pub fn deprioritized_segments(&self) -> impl Iterator<Item = &SegmentReport> {
+        self.segments.iter().filter(|s| s.deprioritized)
+    }
+
+    /// Returns the segments where locking was attempted and failed.
+    pub fn unlocked_segments(&self) -> impl Iterator<Item = &SegmentReport> {
+        self.segments
+            .iter()
+            .filter(|s| matches!(s.mlock, Some(Err(_))))
+    }
+
+    /// Returns the total bytes successfully remapped, including padding.
+    pub fn remapped_bytes(&self) -> usize {
+        self.segments
+            .iter()
+            .filter_map(|s| s.remapped())
+            .map(Range::len)
+            .sum()
+    }
+
+    /// Returns the total bytes of padding added by remapping: the portion of remapped ranges
+    /// outside the segments' own pages.
+    pub fn padding_bytes(&self) -> usize {
+        self.segments
+            .iter()
+            .filter_map(|s| {
+                let remapped = s.remapped()?;
+                let pages = s.page_range(self.base_page_size);
+                let overlap = std::cmp::max(remapped.start, pages.start)
+                    ..std::cmp::min(remapped.end, pages.end);
+                Some(remapped.len() - overlap.len())
+            })
+            .sum()
+    }
+
+    /// Returns the total number of huge pages consumed by remapping, excluding large pages and
+    /// mTHP pages.
+    pub fn huge_pages(&self) -> usize {
+        match self.huge_page_size {
+            Some(s) => (self.remapped_bytes() - self.large_page_bytes() - self.mthp_bytes()) / s,
+            None => 0,
+        }
+    }
+
+    /// Returns the total bytes remapped at a smaller mTHP size.
+    pub fn mthp_bytes(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|s| s.mthp_size.is_some())
+            .filter_map(|s| s.remapped())
+            .map(Range::len)
+            .sum()
+    }
+
+    /// Returns the total bytes remapped into pages of [`Report::large_page_size`].
+    pub fn large_page_bytes(&self) -> usize {
+        self.segments
+            .iter()
+            .filter_map(|s| s.large_pages.as_ref())
+            .map(Range::len)
+            .sum()
+    }
+
+    /// Returns the total number of pages of [`Report::large_page_size`] consumed by remapping.
+    pub fn large_pages(&self) -> usize {
+        match self.large_page_size {
+            Some(s) => self.large_page_bytes() / s,
+            None => 0,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Report {
+    /// Writes this report as JSON to `path`, e.g. `/run/myapp/page-primer.json`.
+    ///
+    /// The file is written to a temporary name and renamed into place, so readers never see a
+    /// partial report.
+    pub fn write_json(&self, path: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(std::io::Error::from)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+/// Describes ELF `p_flags` in the style of `/proc/<pid>/maps`.
+pub(crate) struct DebugProt(pub(crate) u32);
+
+impl std::fmt::Display for DebugProt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let r = if (self.0 & PF_R) != 0 { "r" } else { "-" };
+        let w = if (self.0 & PF_W) != 0 { "w" } else { "-" };
+        let x = if (self.0 & PF_X) != 0 { "x" } else { "-" };
+        write!(f, "{r}{w}{x}")
+    }
+}
+
+/// Writes a human-readable description, as logged by [`crate::Output::log`].
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(s) = self.skipped {
+            return write!(f, "skipped page priming: {s}");
+        }
+        writeln!(f, "primed pages:")?;
+        let mut last_object_i = None;
+        for seg in &self.segments {
+            if Some(seg.object_i) != last_object_i {
+                if let Some(obj) = self.objects.get(seg.object_i) {
+                    writeln!(f, "object {}:", obj.path.display())?;
+                }
+            }
+            write!(
+                f,
+                "* {:012x}-{:012x} {} ->",
+                seg.addrs.start,
+                seg.addrs.end,
+                DebugProt(seg.flags)
+            )?;
+            for (strategy, e) in seg.remap_fallbacks.iter() {
+                write!(f, " remap[{strategy}]={e}")?;
+            }
+            let strategy = match seg.remap_strategy {
+                Some(s) if self.remap_strategies.len() > 1 => format!("[{s}]"),
+                _ => String::new(),
+            };
+            match seg.remap.as_ref() {
+                Some(Ok(remapped)) => write!(
+                    f,
+                    " remap{strategy}={:012x}-{:012x}",
+                    remapped.start, remapped.end
+                )?,
+                Some(Err(e)) => write!(f, " remap{strategy}={e}")?,
+                None => {}
+            }
+            if let Some(s) = seg.mthp_size {
+                write!(f, " mthp={s}")?;
+            }
+            if let Some(large) = seg.large_pages.as_ref() {
+                write!(f, " large={:012x}-{:012x}", large.start, large.end)?;
+            }
+            let mode = match seg.mlock_mode {
+                Some(m) if m != LockMode::Populate => format!("[{m}]"),
+                _ => String::new(),
+            };
+            if seg.deprioritized {
+                write!(f, " deprioritized")?;
+            }
+            if let Some(a) = seg.filter.filter(|&a| a != Action::BOTH) {
+                write!(f, " filter={a}")?;
+            }
+            match seg.mlock.as_ref() {
+                Some(Ok(())) => write!(f, " mlock{mode}=success")?,
+                Some(Err(e)) => write!(f, " mlock{mode}={}", Error::from_raw_os_error(*e))?,
+                None => {}
+            }
+            if let Some(c) = seg.coverage.as_ref() {
+                write!(f, " verified: huge={} locked={}", c.huge_bytes(), c.locked)?;
+                write!(f, " page_sizes=")?;
+                for (i, s) in c.kernel_page_sizes.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+                    write!(f, "{sep}{s}")?;
+                }
+            }
+            writeln!(f)?;
+            last_object_i = Some(seg.object_i);
+        }
+        write!(
+            f,
+            "totals: locked={} remapped={} padding={} huge_pages={}",
+            self.locked_bytes(),
+            self.remapped_bytes(),
+            self.padding_bytes(),
+            self.huge_pages()
+        )?;
+        if self.large_page_size.is_some() {
+            write!(f, " large_pages={}", self.large_pages())?;
+        }
+        if let Some(v) = self.vm_lck {
+            write!(f, " VmLck={v}")?;
+        }
+        if let Some(b) = self.budget {
+            let deprioritized = self.deprioritized_segments().count();
+            write!(f, " budget={b} deprioritized={deprioritized}")?;
+        }
+        if let Some(m) = self.memlock.as_ref().filter(|m| m.shortfall > 0) {
+            write!(f, " memlock_short={}", m.shortfall)?;
+        }
+        if !self.signals_deferred.is_empty() {
+            write!(f, " signals_deferred={:?}", self.signals_deferred)?;
+        }
+        if let Some(d) = self.world_stopped {
+            write!(f, " world_stopped={d:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An error remapping a segment into huge pages.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum HugeError {
+    /// The segment is not readable, so it can't be copied.
+    Unreadable,
+
+    /// Other mappings occupy every huge page the segment touches.
+    Conflict,
+
+    /// The segment is writable, so its contents can't be trusted not to change while copying.
+    Writable,
+
+    /// `memfd_create` failed with the given `errno`.
+    MemfdCreateFailed(i32),
+
+    /// `ftruncate` failed with the given `errno`.
+    FtruncateFailed(i32),
+
+    /// Creating the temporary mapping for copying failed with the given `errno`.
+    InitialMmapFailed(i32),
+
+    /// Moving the new mapping over the original segment failed with the given `errno`.
+    RemapFailed(i32),
+
+    /// `madvise` failed with the given `errno`.
+    MadviseFailed(i32),
+
+    /// `mprotect` failed with the given `errno`.
+    MprotectFailed(i32),
+
+    /// The segment contains no whole huge page, as required to collapse in place.
+    TooSmall,
+
+    /// `madvise(MADV_COLLAPSE)` failed with the given `errno`, e.g. `EINVAL` on kernels before
+    /// 6.1 or without `CONFIG_READ_ONLY_THP_FOR_FS`, or `EAGAIN` on transient failure.
+    CollapseFailed(i32),
+}
+
+impl std::fmt::Display for HugeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HugeError::Unreadable => write!(f, "unreadable"),
+            HugeError::Conflict => write!(f, "conflicting mappings within all relevant huge pages"),
+            HugeError::Writable => write!(f, "writable"),
+            HugeError::MemfdCreateFailed(e) => {
+                write!(f, "memfd_create failed: {}", Error::from_raw_os_error(*e))
+            }
+            HugeError::FtruncateFailed(e) => {
+                write!(f, "ftruncate failed: {}", Error::from_raw_os_error(*e))
+            }
+            HugeError::InitialMmapFailed(e) => {
+                write!(f, "initial mmap failed: {}", Error::from_raw_os_error(*e))
+            }
+            HugeError::RemapFailed(e) => {
+                write!(f, "remap failed: {}", Error::from_raw_os_error(*e))
+            }
+            HugeError::MadviseFailed(e) => {
+                write!(f, "madvise failed: {}", Error::from_raw_os_error(*e))
+            }
+            HugeError::MprotectFailed(e) => {
+                write!(f, "mprotect failed: {}", Error::from_raw_os_error(*e))
+            }
+            HugeError::TooSmall => write!(f, "contains no whole huge page"),
+            HugeError::CollapseFailed(e) => {
+                write!(f, "MADV_COLLAPSE failed: {}", Error::from_raw_os_error(*e))
+            }
+        }
+    }
+}
+
+impl std::error::Error for HugeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Report {
+        Report {
+            base_page_size: 0x1000,
+            huge_page_size: Some(0x200000),
+            objects: vec![ObjectReport {
+                index: 0,
+                path: PathBuf::from("/bin/foo"),
+                load_bias: 0,
+                is_main: true,
+            }],
+            segments: vec![
+                SegmentReport {
+                    remap: Some(Ok(0x200000..0x400000)),
+                    remap_strategy: Some(RemapStrategy::AnonThp),
+                    remap_fallbacks: {
+                        let mut f = Fallbacks::default();
+                        f.push(
+                            RemapStrategy::HugetlbMemfd,
+                            HugeError::FtruncateFailed(libc::ENOMEM),
+                        );
+                        f
+                    },
+                    mlock: Some(Ok(())),
+                    mlock_mode: Some(LockMode::Populate),
+                    ..SegmentReport::for_test(PF_R | PF_X, 0x201000..0x3ff800)
+                },
+                SegmentReport {
+                    remap: Some(Err(HugeError::Writable)),
+                    mlock: Some(Err(libc::ENOMEM)),
+                    mlock_mode: Some(LockMode::OnFault),
+                    ..SegmentReport::for_test(PF_R | PF_W, 0x400000..0x401000)
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn totals() {
+        let report = sample();
+        assert_eq!(report.locked_bytes(), 0x200000);
+        assert_eq!(report.remapped_bytes(), 0x200000);
+        assert_eq!(report.padding_bytes(), 0x1000);
+        assert_eq!(report.huge_pages(), 1);
+    }
+
+    #[test]
+    fn display_lock_mode() {
+        let text = sample().to_string();
+        assert!(text.contains(" mlock=success"), "{}", text);
+        assert!(text.contains(" mlock[mlock2 on fault]="), "{}", text);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        let report = sample();
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::from_str::<Report>(&json).unwrap(), report);
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Parsing of the `PAGE_PRIMER` environment variable.
+
+use crate::{Budget, Filter, LockMode, Options, RemapStrategy};
+
+/// Options parsed from a `PAGE_PRIMER` environment variable, as used by the
+/// `page-primer-preload` and `page-primer-audit` libraries.
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Spec {
+    /// The priming options given by the recognized items.
+    pub options: Options,
+
+    /// True iff `quiet` was given, so the log shouldn't be printed.
+    pub quiet: bool,
+
+    /// Messages about unrecognized items, which are otherwise ignored.
+    pub warnings: Vec<String>,
+}
+
+fn parse_strategy(s: &str) -> Option<&'static [RemapStrategy]> {
+    Some(match s {
+        "hugetlb" => &[RemapStrategy::HugetlbMemfd],
+        "collapse" => &[RemapStrategy::Collapse],
+        "shmem" => &[RemapStrategy::ShmemMemfd],
+        "anon" => &[RemapStrategy::AnonThp],
+        "auto" => RemapStrategy::AUTO,
+        _ => return None,
+    })
+}
+
+fn parse_lock_mode(s: &str) -> Option<LockMode> {
+    Some(match s {
+        "populate" => LockMode::Populate,
+        "onfault" => LockMode::OnFault,
+        "all" => LockMode::All,
+        "all-onfault" => LockMode::AllOnFault,
+        _ => return None,
+    })
+}
+
+fn parse_budget(s: &str) -> Option<Budget> {
+    match s.strip_suffix('%') {
+        Some(p) => p
+            .parse()
+            .ok()
+            .filter(|&p| p <= 100)
+            .map(Budget::CgroupPercent),
+        None => s.parse().ok().map(Budget::Bytes),
+    }
+}
+
+impl Spec {
+    /// Parses `spec`, a comma-separated list of:
+    ///
+    /// *   `mlock`: see [`Options::mlock`].
+    /// *   `mlock=<mode>`: locks with the given [`LockMode`], one of `populate`, `onfault`,
+    ///     `all`, or `all-onfault`.
+    /// *   `remap`: see [`Options::remap`].
+    /// *   `remap=<strategy>[+<strategy>...]`: remaps with the given strategies, each one of
+    ///     `hugetlb`, `collapse`, `shmem`, `anon`, or `auto` for all of them.
+    /// *   `verify`: see [`Options::verify`].
+    /// *   `budget=<bytes>` or `budget=<percent>%`: see [`Options::budget`]; a percentage is of
+    ///     the cgroup memory limit.
+    /// *   `prefer=<prefix>[:<prefix>...]`: see [`Options::prefer_objects`].
+    /// *   `only=<glob>`: primes only objects whose path matches; see [`Filter::path_glob`].
+    /// *   `main-only`, `exec-only`, `skip-writable`: see [`Filter::main_only`],
+    ///     [`Filter::executable_only`], and [`Filter::skip_writable`].
+    /// *   `manifest=<path>`: writes the report as JSON to `path`; requires the `serde` feature.
+    /// *   `quiet`: sets [`Spec::quiet`].
+    pub fn parse(spec: &str) -> Self {
+        let mut options = crate::prime();
+        let mut strategies = Vec::new();
+        let mut quiet = false;
+        let mut warnings = Vec::new();
+        for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (key, value) = match item.split_once('=') {
+                Some((k, v)) => (k, Some(v)),
+                None => (item, None),
+            };
+            match (key, value) {
+                ("mlock", None) => options = options.mlock(true),
+                ("mlock", Some(v)) => match parse_lock_mode(v) {
+                    Some(m) => options = options.mlock(true).lock_mode(m),
+                    None => warnings.push(format!("unknown lock mode {v:?}")),
+                },
+                ("remap", None) => options = options.remap(true),
+                ("remap", Some(v)) => {
+                    options = options.remap(true);
+                    for s in v.split('+') {
+                        match parse_strategy(s) {
+                            Some(s) => strategies.extend_from_slice(s),
+                            None => warnings.push(format!("unknown remap strategy {s:?}")),
+                        }
+                    }
+                }
+                ("verify", None) => options = options.verify(true),
+                ("budget", Some(v)) => match parse_budget(v) {
+                    Some(b) => options = options.budget(b),
+                    None => warnings.push(format!("unknown budget {v:?}")),
+                },
+                ("prefer", Some(v)) => options = options.prefer_objects(v.split(':')),
+                ("only", Some(v)) => options = options.filter(Filter::path_glob(v)),
+                ("main-only", None) => options = options.filter(Filter::main_only()),
+                ("exec-only", None) => options = options.filter(Filter::executable_only()),
+                ("skip-writable", None) => options = options.filter(Filter::skip_writable()),
+                #[cfg(feature = "serde")]
+                ("manifest", Some(path)) if !path.is_empty() => options = options.manifest(path),
+                ("quiet", None) => quiet = true,
+                _ => warnings.push(format!("unknown item {item:?}")),
+            }
+        }
+        if !strategies.is_empty() {
+            options = options.remap_strategies(strategies);
+        }
+        Spec {
+            options,
+            quiet,
+            warnings,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_spec() {
+        assert_eq!(
+            Spec::parse(
+                "mlock, remap=hugetlb+anon,verify,quiet,budget=50%,prefer=libc.so:libstdc++.so"
+            ),
+            Spec {
+                options: crate::prime()
+                    .mlock(true)
+                    .remap(true)
+                    .remap_strategies([RemapStrategy::HugetlbMemfd, RemapStrategy::AnonThp])
+                    .verify(true)
+                    .budget(Budget::CgroupPercent(50))
+                    .prefer_objects(["libc.so", "libstdc++.so"]),
+                quiet: true,
+                warnings: Vec::new(),
+            }
+        );
+        assert_eq!(
+            Spec::parse("remap=auto,bogus,remap=nope,mlock=all-onfault,budget=101%"),
+            Spec {
+                options: crate::prime()
+                    .remap(true)
+                    .remap_auto()
+                    .mlock(true)
+                    .lock_mode(LockMode::AllOnFault),
+                quiet: false,
+                warnings: vec![
+                    "unknown item \"bogus\"".to_owned(),
+                    "unknown remap strategy \"nope\"".to_owned(),
+                    "unknown budget \"101%\"".to_owned(),
+                ],
+            }
+        );
+
+        #[cfg(feature = "serde")]
+        assert_eq!(
+            Spec::parse("manifest=/tmp/p.json").options,
+            crate::prime().manifest("/tmp/p.json")
+        );
+
+        // Filters are closures, so compare only the warnings.
+        assert!(
+            Spec::parse("only=*/libfoo.so*,main-only,exec-only,skip-writable")
+                .warnings
+                .is_empty()
+        );
+    }
+}
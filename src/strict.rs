@@ -0,0 +1,313 @@
+// Copyright (C) 2024 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Policy for [`crate::Options::try_run`].
+
+use crate::report::{HugeError, Report, Skipped};
+use crate::Output;
+use std::io::Error;
+use std::ops::Range;
+
+/// A policy describing which outcomes [`crate::Options::try_run`] treats as failure.
+///
+/// Regardless of policy, `try_run` fails if priming was skipped entirely (e.g. because other
+/// threads were running) or if remapping was requested but huge pages are unavailable. Finding
+/// nothing new for [`crate::prime_new_objects`] isn't a failure.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[must_use = "Strict does nothing without Options::strict"]
+pub struct Strict {
+    main_text_remapped: bool,
+    all_locked: bool,
+}
+
+impl Strict {
+    /// Requires that every executable segment of the main executable was remapped.
+    ///
+    /// This catches a missing or broken `.cargo/config.toml` alignment setting. It's ignored when
+    /// the main executable wasn't visited, as with [`crate::prime_new_objects`].
+    #[inline]
+    pub fn main_text_remapped(self, main_text_remapped: bool) -> Self {
+        Self {
+            main_text_remapped,
+            ..self
+        }
+    }
+
+    /// Requires that every segment was successfully `mlock`ed.
+    ///
+    /// This catches an insufficient `RLIMIT_MEMLOCK`, e.g. a lost `LimitMEMLOCK=` in a systemd
+    /// unit.
+    #[inline]
+    pub fn all_locked(self, all_locked: bool) -> Self {
+        Self { all_locked, ..self }
+    }
+
+    /// Returns all violations of this policy by `report`.
+    pub(crate) fn check(
+        &self,
+        remap_requested: bool,
+        mlock_requested: bool,
+        report: &Report,
+    ) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        match report.skipped {
+            None => {}
+            Some(Skipped::NoNewObjects) => return violations,
+            Some(s) => {
+                violations.push(Violation::Skipped(s));
+                return violations;
+            }
+        }
+        if let (true, Some(s)) = (remap_requested, report.remap_skipped) {
+            violations.push(Violation::RemapSkipped(s));
+        } else if remap_requested && report.huge_page_size.is_none() {
+            violations.push(Violation::RemapUnavailable);
+        }
+        let main = report.objects.iter().find(|o| o.is_main).map(|o| o.index);
+        if let (true, true, Some(main)) = (
+            self.main_text_remapped,
+            report.huge_page_size.is_some(),
+            main,
+        ) {
+            for seg in report
+                .object_segments(main)
+                .filter(|s| s.is_executable() && s.remap_wanted())
+            {
+                match &seg.remap {
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => violations.push(Violation::MainTextNotRemapped {
+                        addrs: seg.addrs.clone(),
+                        error: Some(*e),
+                    }),
+                    None => violations.push(Violation::MainTextNotRemapped {
+                        addrs: seg.addrs.clone(),
+                        error: None,
+                    }),
+                }
+            }
+        }
+        if self.all_locked && mlock_requested {
+            for seg in report.segments.iter().filter(|s| s.lock_wanted()) {
+                match seg.mlock {
+                    Some(Ok(())) => {}
+                    Some(Err(errno)) => violations.push(Violation::NotLocked {
+                        object_i: seg.object_i,
+                        addrs: seg.addrs.clone(),
+                        errno: Some(errno),
+                    }),
+                    None => violations.push(Violation::NotLocked {
+                        object_i: seg.object_i,
+                        addrs: seg.addrs.clone(),
+                        errno: None,
+                    }),
+                }
+            }
+        }
+        violations
+    }
+}
+
+/// A single way in which priming fell short of a [`Strict`] policy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Violation {
+    /// Priming was skipped entirely.
+    Skipped(Skipped),
+
+    /// Remapping was requested but huge pages are unavailable.
+    RemapUnavailable,
+
+    /// Remapping was requested but skipped while locking proceeded.
+    RemapSkipped(Skipped),
+
+    /// An executable segment of the main executable was not remapped.
+    MainTextNotRemapped {
+        addrs: Range<usize>,
+        error: Option<HugeError>,
+    },
+
+    /// A segment was not locked. `errno` is `None` if locking wasn't attempted.
+    NotLocked {
+        object_i: usize,
+        addrs: Range<usize>,
+        errno: Option<i32>,
+    },
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::Skipped(s) => write!(f, "priming skipped: {s}"),
+            Violation::RemapUnavailable => {
+                write!(f, "remapping requested but huge pages unavailable")
+            }
+            Violation::RemapSkipped(s) => write!(f, "remapping skipped: {s}"),
+            Violation::MainTextNotRemapped { addrs, error } => {
+                write!(
+                    f,
+                    "main executable text {:012x}-{:012x} not remapped",
+                    addrs.start, addrs.end
+                )?;
+                if let Some(e) = error {
+                    write!(f, ": {e}")?;
+                }
+                Ok(())
+            }
+            Violation::NotLocked {
+                object_i,
+                addrs,
+                errno,
+            } => {
+                write!(
+                    f,
+                    "object {object_i} segment {:012x}-{:012x} not locked",
+                    addrs.start, addrs.end
+                )?;
+                if let Some(e) = errno {
+                    write!(f, ": {}", Error::from_raw_os_error(*e))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The error returned by [`crate::Options::try_run`] when priming violates the [`Strict`] policy.
+///
+/// The full [`Output`] is retained so it can still be logged.
+pub struct PrimeError(Box<PrimeErrorInner>);
+
+struct PrimeErrorInner {
+    output: Output,
+    violations: Vec<Violation>,
+}
+
+impl PrimeError {
+    pub(crate) fn new(output: Output, violations: Vec<Violation>) -> Self {
+        PrimeError(Box::new(PrimeErrorInner { output, violations }))
+    }
+}
+
+impl PrimeError {
+    /// Returns all policy violations; never empty.
+    #[inline]
+    pub fn violations(&self) -> &[Violation] {
+        &self.0.violations
+    }
+
+    /// Returns the output of the run which violated the policy.
+    #[inline]
+    pub fn output(&self) -> &Output {
+        &self.0.output
+    }
+
+    /// Returns the output of the run which violated the policy, discarding the violations.
+    #[inline]
+    pub fn into_output(self) -> Output {
+        self.0.output
+    }
+}
+
+impl std::fmt::Debug for PrimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrimeError")
+            .field("violations", &self.0.violations)
+            .field("report", self.0.output.report())
+            .finish()
+    }
+}
+
+impl std::fmt::Display for PrimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "page priming policy violated: ")?;
+        for (i, v) in self.0.violations.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{v}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PrimeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{ObjectReport, SegmentReport};
+
+    #[test]
+    fn check() {
+        let strict = Strict::default().main_text_remapped(true).all_locked(true);
+        let skipped = Report {
+            skipped: Some(Skipped::ThreadsRunning(2)),
+            ..Default::default()
+        };
+        assert_eq!(
+            strict.check(true, true, &skipped),
+            vec![Violation::Skipped(Skipped::ThreadsRunning(2))]
+        );
+        let nothing_new = Report {
+            skipped: Some(Skipped::NoNewObjects),
+            ..Default::default()
+        };
+        assert_eq!(strict.check(true, true, &nothing_new), vec![]);
+        let lock_only = Report {
+            remap_skipped: Some(Skipped::ThreadsRunning(2)),
+            ..Default::default()
+        };
+        assert_eq!(
+            strict.check(true, true, &lock_only),
+            vec![Violation::RemapSkipped(Skipped::ThreadsRunning(2))]
+        );
+
+        let mut report = Report {
+            base_page_size: 0x1000,
+            huge_page_size: Some(0x200000),
+            objects: vec![ObjectReport {
+                index: 0,
+                path: "/bin/foo".into(),
+                load_bias: 0,
+                is_main: true,
+            }],
+            segments: vec![SegmentReport {
+                remap: Some(Err(HugeError::Conflict)),
+                remap_strategy: Some(crate::RemapStrategy::HugetlbMemfd),
+                mlock: Some(Err(libc::EPERM)),
+                mlock_mode: Some(crate::LockMode::Populate),
+                ..SegmentReport::for_test(
+                    crate::report::PF_R | crate::report::PF_X,
+                    0x200000..0x400000,
+                )
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            strict.check(true, true, &report),
+            vec![
+                Violation::MainTextNotRemapped {
+                    addrs: 0x200000..0x400000,
+                    error: Some(HugeError::Conflict),
+                },
+                Violation::NotLocked {
+                    object_i: 0,
+                    addrs: 0x200000..0x400000,
+                    errno: Some(libc::EPERM),
+                },
+            ]
+        );
+        assert!(Strict::default().check(true, true, &report).is_empty());
+
+        // A later run which didn't visit the main executable.
+        report.objects[0].is_main = false;
+        assert_eq!(
+            strict.check(true, true, &report),
+            vec![Violation::NotLocked {
+                object_i: 0,
+                addrs: 0x200000..0x400000,
+                errno: Some(libc::EPERM),
+            }]
+        );
+    }
+}